@@ -0,0 +1,175 @@
+use gmodx::lua::{self, Methods, State, Table, UserData, UserDataRef};
+
+// byte-aligned counterpart of the net library, everything is written little-endian like net does
+#[derive(Default)]
+pub struct Buffer {
+    data: Vec<u8>,
+}
+
+fn check_bits(bits: u32) -> lua::Result<usize> {
+    if !(8..=64).contains(&bits) || !bits.is_multiple_of(8) {
+        return Err(lua::Error::Runtime(format!("invalid bit count {bits} (expected 8, 16, 24, ... or 64)").into()));
+    }
+
+    Ok(bits as usize / 8)
+}
+
+// both return the size in bytes
+fn check_int(value: i64, bits: u32) -> lua::Result<usize> {
+    let size = check_bits(bits)?;
+    let min = i64::MIN >> (64 - bits);
+    let max = i64::MAX >> (64 - bits);
+    if value < min || value > max {
+        return Err(lua::Error::Runtime(format!("{value} doesn't fit in a signed {bits} bit integer").into()));
+    }
+
+    Ok(size)
+}
+
+fn check_uint(value: i64, bits: u32) -> lua::Result<usize> {
+    let size = check_bits(bits)?;
+    let max = u64::MAX >> (64 - bits);
+    if value < 0 || value as u64 > max {
+        return Err(lua::Error::Runtime(format!("{value} doesn't fit in an unsigned {bits} bit integer").into()));
+    }
+
+    Ok(size)
+}
+
+fn write_int(_: &State, this: UserDataRef<Buffer>, value: i64, bits: u32) -> lua::Result<()> {
+    let size = check_int(value, bits)?;
+    this.borrow_mut().data.extend_from_slice(&value.to_le_bytes()[..size]);
+    Ok(())
+}
+
+fn write_uint(_: &State, this: UserDataRef<Buffer>, value: i64, bits: u32) -> lua::Result<()> {
+    let size = check_uint(value, bits)?;
+    this.borrow_mut().data.extend_from_slice(&(value as u64).to_le_bytes()[..size]);
+    Ok(())
+}
+
+fn write_bool(_: &State, this: UserDataRef<Buffer>, value: bool) {
+    this.borrow_mut().data.push(value as u8);
+}
+
+fn write_float(_: &State, this: UserDataRef<Buffer>, value: f64) {
+    this.borrow_mut().data.extend_from_slice(&(value as f32).to_le_bytes());
+}
+
+fn write_double(_: &State, this: UserDataRef<Buffer>, value: f64) {
+    this.borrow_mut().data.extend_from_slice(&value.to_le_bytes());
+}
+
+// null-terminated, same as net.WriteString
+fn write_string(_: &State, this: UserDataRef<Buffer>, value: lua::String) -> lua::Result<()> {
+    let value = value.as_bytes();
+    if value.contains(&0) {
+        return Err(lua::Error::Runtime("string can't contain null bytes (use write_data instead)".into()));
+    }
+
+    let mut buffer = this.borrow_mut();
+    buffer.data.extend_from_slice(value);
+    buffer.data.push(0);
+    Ok(())
+}
+
+fn write_data(_: &State, this: UserDataRef<Buffer>, value: lua::String, length: Option<usize>) {
+    let value = value.as_bytes();
+    let length = length.map_or(value.len(), |length| length.min(value.len()));
+    this.borrow_mut().data.extend_from_slice(&value[..length]);
+}
+
+fn bytes(l: &State, this: UserDataRef<Buffer>) -> lua::String {
+    l.create_string(&this.borrow().data)
+}
+
+fn len(_: &State, this: UserDataRef<Buffer>) -> usize {
+    this.borrow().data.len()
+}
+
+fn clear(_: &State, this: UserDataRef<Buffer>) {
+    this.borrow_mut().data.clear();
+}
+
+impl UserData for Buffer {
+    fn methods(m: &mut Methods) {
+        m.add(c"write_int", write_int);
+        m.add(c"writeInt", write_int);
+        m.add(c"write_uint", write_uint);
+        m.add(c"writeUInt", write_uint);
+        m.add(c"write_bool", write_bool);
+        m.add(c"writeBool", write_bool);
+        m.add(c"write_float", write_float);
+        m.add(c"writeFloat", write_float);
+        m.add(c"write_double", write_double);
+        m.add(c"writeDouble", write_double);
+        m.add(c"write_string", write_string);
+        m.add(c"writeString", write_string);
+        m.add(c"write_data", write_data);
+        m.add(c"writeData", write_data);
+        m.add(c"bytes", bytes);
+        m.add(c"len", len);
+        m.add(c"clear", clear);
+    }
+
+    fn meta_methods(m: &mut Methods) {
+        m.add(c"__len", len);
+        m.add(c"__tostring", |_: &State, this: UserDataRef<Buffer>| {
+            format!("tungstenite buffer ({} bytes)", this.borrow().data.len())
+        });
+    }
+
+    fn name() -> &'static str {
+        "tungstenite_buffer"
+    }
+}
+
+pub fn on_gmod_open(state: &State, table: &Table) {
+    table.raw_set(state, "buffer", state.create_function(|l: &State| {
+        l.create_userdata(Buffer::default())
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_counts_have_to_be_whole_bytes() {
+        assert_eq!(check_bits(8).unwrap(), 1);
+        assert_eq!(check_bits(24).unwrap(), 3);
+        assert_eq!(check_bits(64).unwrap(), 8);
+
+        for bits in [0, 4, 12, 72] {
+            assert!(check_bits(bits).is_err(), "{bits} bits");
+        }
+    }
+
+    #[test]
+    fn signed_values_have_to_fit() {
+        assert_eq!(check_int(127, 8).unwrap(), 1);
+        assert_eq!(check_int(-128, 8).unwrap(), 1);
+        assert!(check_int(128, 8).is_err());
+        assert!(check_int(-129, 8).is_err());
+
+        assert_eq!(check_int(-8_388_608, 24).unwrap(), 3);
+        assert!(check_int(8_388_608, 24).is_err());
+
+        assert_eq!(check_int(i64::MIN, 64).unwrap(), 8);
+        assert_eq!(check_int(i64::MAX, 64).unwrap(), 8);
+        assert!(check_int(0, 7).is_err());
+    }
+
+    #[test]
+    fn unsigned_values_have_to_fit() {
+        assert_eq!(check_uint(255, 8).unwrap(), 1);
+        assert!(check_uint(256, 8).is_err());
+        assert!(check_uint(-1, 8).is_err());
+
+        assert_eq!(check_uint(16_777_215, 24).unwrap(), 3);
+        assert!(check_uint(16_777_216, 24).is_err());
+
+        assert_eq!(check_uint(i64::MAX, 64).unwrap(), 8);
+        assert!(check_uint(-1, 64).is_err());
+    }
+}
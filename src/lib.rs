@@ -1,3 +1,4 @@
+pub mod buffer;
 pub mod config;
 pub mod socket;
 
@@ -12,6 +13,7 @@ fn gmod13_open(state: State) {
 
     //
     socket::on_gmod_open(&state, &table);
+    buffer::on_gmod_open(&state, &table);
 
     //
     state
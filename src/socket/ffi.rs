@@ -0,0 +1,39 @@
+use std::ffi::{CStr, c_char};
+use std::slice;
+
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+use crate::socket::handler;
+use crate::socket::types::SocketCommand;
+
+// lets other native modules send through a socket from any thread, the message goes through
+// the same queue as `tungstenite:send`. `handle` is what `tungstenite:get_send_handle` returns.
+// returns false if the handle is invalid, the socket is gone or `binary` is false and data isn't utf-8
+
+/// # Safety
+///
+/// `handle` must be a valid nul-terminated string and `data` must point to `len` readable bytes
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tungstenite_send(handle: *const c_char, data: *const u8, len: usize, binary: bool) -> bool {
+    if handle.is_null() || (data.is_null() && len > 0) {
+        return false;
+    }
+
+    let handle = unsafe { CStr::from_ptr(handle) };
+    let Some(id) = handle.to_str().ok().and_then(|handle| Uuid::parse_str(handle).ok()) else {
+        return false;
+    };
+
+    let data = if len == 0 { Vec::new() } else { unsafe { slice::from_raw_parts(data, len) }.to_vec() };
+    let message = if binary {
+        Message::Binary(data.into())
+    } else {
+        match String::from_utf8(data) {
+            Ok(text) => Message::Text(text.into()),
+            Err(_) => return false,
+        }
+    };
+
+    handler::send_command(id, SocketCommand::Send(message, None))
+}
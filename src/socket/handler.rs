@@ -1,29 +1,51 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::sync::{LazyLock, Mutex};
 
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
-use gmodx::{is_closed, next_tick, lua::{AnyUserData, Function, ObjectLike}, tokio_tasks};
-use tokio::sync::mpsc;
-use tokio::time::{self, Duration, MissedTickBehavior};
-use tokio_tungstenite::{connect_async, tungstenite::{self, Message, Utf8Bytes, protocol::CloseFrame}};
+use gmodx::{is_closed, next_tick, lua::{self, AnyUserData, Function, ObjectLike, Table}};
+use serde_json::Value;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::fs::{self, File};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpStream, lookup_host};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc, oneshot};
+use tokio::time::{self, Duration, Instant, MissedTickBehavior};
+use tokio_socks::TargetAddr;
+use tokio_socks::tcp::Socks5Stream;
+use tokio_tungstenite::{Connector, MaybeTlsStream, client_async_tls_with_config, connect_async, connect_async_tls_with_config};
+use tokio_tungstenite::tungstenite::{self, Message, Utf8Bytes, client::IntoClientRequest, error::UrlError, protocol::{CloseFrame, frame::coding::CloseCode}};
+use tokio_tungstenite::tungstenite::handshake::client::{Request, Response};
+use tokio_tungstenite::tungstenite::http::{self, HeaderValue, header};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use crate::socket::types::{SocketCommand, SocketMetadata, SocketState};
+use crate::socket::options::{Socks5Proxy, TlsBackend};
+use crate::socket::pool;
+use crate::socket::tasks;
+use crate::socket::types::{DisconnectInfo, Payload, PendingRequest, SocketCommand, SocketError, SocketMetadata, SocketState, SocketStream, gwsockets_callback, now_millis};
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+// send_file paths are relative to this, the server runs from the directory containing `garrysmod`
+const FILE_ROOT: &str = "garrysmod/data";
 
 struct SocketRegistryEntry {
     target: AnyUserData,
+    meta: Arc<SocketMetadata>,
     sender: Option<mpsc::UnboundedSender<SocketCommand>>,
 }
 
-static SOCKETS: LazyLock<Mutex<std::collections::HashMap<Uuid, SocketRegistryEntry>>> =
-    LazyLock::new(|| Mutex::new(std::collections::HashMap::new()));
+static SOCKETS: LazyLock<Mutex<HashMap<Uuid, SocketRegistryEntry>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
 
-pub fn register(id: Uuid, target: AnyUserData) {
+pub fn register(id: Uuid, target: AnyUserData, meta: Arc<SocketMetadata>) {
     SOCKETS.lock().unwrap().insert(
         id,
-        SocketRegistryEntry { target, sender: None },
+        SocketRegistryEntry { target, meta, sender: None },
     );
 }
 
@@ -37,39 +59,602 @@ pub fn unregister(id: Uuid) {
     SOCKETS.lock().unwrap().remove(&id);
 }
 
+static LOGGER: Mutex<Option<Function>> = Mutex::new(None);
+
+pub fn set_logger(logger: Option<Function>) {
+    *LOGGER.lock().unwrap() = logger;
+}
+
 pub fn shutdown_all() {
-    let mut sockets = SOCKETS.lock().unwrap();
-    for entry in sockets.values() {
-        if let Some(sender) = &entry.sender {
-            let _ = sender.send(SocketCommand::CloseNow);
+    *LOGGER.lock().unwrap() = None;
+
+    {
+        let mut sockets = SOCKETS.lock().unwrap();
+        for entry in sockets.values() {
+            if let Some(sender) = &entry.sender {
+                let _ = sender.send(SocketCommand::CloseNow);
+            }
         }
+
+        sockets.clear();
     }
 
-    sockets.clear();
+    pool::clear();
+    tasks::shutdown();
 }
 
-fn get_target(id: Uuid) -> Option<AnyUserData> {
+pub fn get_target(id: Uuid) -> Option<AnyUserData> {
     SOCKETS.lock().unwrap().get(&id).map(|entry| entry.target.clone())
 }
 
+pub fn stats(l: &lua::State) -> Table {
+    let mut total = 0;
+    let mut by_state: HashMap<&'static str, u64> = HashMap::new();
+    let mut by_host: HashMap<String, u64> = HashMap::new();
+
+    for entry in SOCKETS.lock().unwrap().values() {
+        total += 1;
+        *by_state.entry(entry.meta.state.get().as_str()).or_default() += 1;
+
+        let host = entry.meta.url().parse::<http::Uri>().ok().and_then(|uri| uri.host().map(str::to_string));
+        *by_host.entry(host.unwrap_or_default()).or_default() += 1;
+    }
+
+    let states = l.create_table();
+    for (state, count) in by_state {
+        states.raw_set(l, state, count);
+    }
+
+    let hosts = l.create_table();
+    for (host, count) in by_host {
+        hosts.raw_set(l, host.as_str(), count);
+    }
+
+    let stats = l.create_table();
+    stats.raw_set(l, "total", total);
+    stats.raw_set(l, "by_state", states);
+    stats.raw_set(l, "by_host", hosts);
+
+    stats
+}
+
+// safe to call from any thread, the worker is the only one touching the stream
+pub fn send_command(id: Uuid, command: SocketCommand) -> bool {
+    SOCKETS
+        .lock()
+        .unwrap()
+        .get(&id)
+        .and_then(|entry| entry.sender.as_ref())
+        .is_some_and(|sender| sender.send(command).is_ok())
+}
+
+fn get_entry(id: Uuid) -> Option<(AnyUserData, Arc<SocketMetadata>)> {
+    SOCKETS.lock().unwrap().get(&id).map(|entry| (entry.target.clone(), Arc::clone(&entry.meta)))
+}
+
+// handlers called through `coroutine.wrap` can yield and resume themselves later
+fn wrap_in_coroutine(l: &lua::State, func: Function) -> lua::Result<Function> {
+    let coroutine = l.get_global::<Table>("coroutine")?;
+    let wrap = coroutine.get::<Function>(l, "wrap")?;
+    wrap.call::<Function>(l, func)
+}
+
+// catches the common `onMessage` / `onmessage` typos for `on_message`
+fn find_misnamed_callback(l: &lua::State, target: &AnyUserData, name: &str) -> Option<String> {
+    let event = name.strip_prefix("on_")?;
+    let mut chars = event.chars();
+    let camel = format!("on{}{}", chars.next()?.to_uppercase(), chars.as_str());
+    let flat = format!("on{}", event.replace('_', ""));
+
+    [camel, flat]
+        .into_iter()
+        .find(|candidate| matches!(target.get::<Option<Function>>(l, candidate.as_str()), Ok(Some(_))))
+}
+
+// looks up a callback (falling back to its gwsockets name unless compat is off), `None` if it isn't set
+fn find_callback(l: &lua::State, target: &AnyUserData, meta: &SocketMetadata, name: &'static str) -> Option<lua::Result<Function>> {
+    let func = target.get::<Function>(l, name).or_else(|err| match gwsockets_callback(name) {
+        Some(alias) if meta.compat => target.get::<Function>(l, alias),
+        _ => Err(err),
+    });
+
+    let Ok(func) = func else {
+        if let Some(misnamed) = find_misnamed_callback(l, target, name)
+            && meta.mark_misnamed_warned(name)
+        {
+            l.error_no_halt_with_stack(&format!("tungstenite: '{misnamed}' is not a callback, did you mean '{name}'?"));
+        }
+
+        return None;
+    };
+
+    if meta.options.coroutine {
+        Some(wrap_in_coroutine(l, func))
+    } else {
+        Some(Ok(func))
+    }
+}
+
+fn run_call<F>(l: &lua::State, id: Uuid, name: &'static str, call: F)
+where
+    F: FnOnce(&lua::State, AnyUserData, Function) -> lua::Result<()>,
+{
+    if is_closed() {
+        return;
+    }
+
+    let Some((target, meta)) = get_entry(id) else {
+        return;
+    };
+
+    call_on(l, target, &meta, name, call);
+}
+
+fn call_on<F>(l: &lua::State, target: AnyUserData, meta: &SocketMetadata, name: &'static str, call: F)
+where
+    F: FnOnce(&lua::State, AnyUserData, Function) -> lua::Result<()>,
+{
+    let Some(func) = find_callback(l, &target, meta, name) else {
+        return;
+    };
+
+    if let Err(err) = func.and_then(|func| call(l, target, func)) {
+        l.error_no_halt_with_stack(&err.to_string());
+    }
+}
+
+fn queue_call<F>(id: Uuid, name: &'static str, call: F)
+where
+    F: FnOnce(&lua::State, AnyUserData, Function) -> lua::Result<()> + Send + 'static,
+{
+    let generation = worker_generation();
+    next_tick(move |l| {
+        if !is_stale(id, generation) {
+            run_call(l, id, name, call);
+        }
+    });
+}
+
 fn queue_callback(id: Uuid, name: &'static str, data: Option<String>) {
+    queue_call(id, name, move |l, target, func| match data {
+        Some(data) => func.call::<()>(l, (target, data)),
+        None => func.call::<()>(l, target),
+    });
+}
+
+macro_rules! set_json {
+    ($l:expr, $table:expr, $key:expr, $value:expr) => {
+        match $value {
+            Value::Null => {}
+            Value::Bool(value) => $table.raw_set($l, $key, *value),
+            Value::Number(value) => $table.raw_set($l, $key, value.as_f64().unwrap_or_default()),
+            Value::String(value) => $table.raw_set($l, $key, value.as_str()),
+            value @ (Value::Array(_) | Value::Object(_)) => $table.raw_set($l, $key, json_table($l, value)),
+        }
+    };
+}
+
+// nulls are left out, lua tables can't hold nil
+fn json_table(l: &lua::State, value: &Value) -> Table {
+    let table = l.create_table();
+    match value {
+        Value::Array(items) => {
+            for (index, item) in items.iter().enumerate() {
+                set_json!(l, table, index + 1, item);
+            }
+        }
+        Value::Object(fields) => {
+            for (key, item) in fields {
+                set_json!(l, table, key.as_str(), item);
+            }
+        }
+        _ => {}
+    }
+
+    table
+}
+
+// counted until the tick runs, so lua can tell when its callbacks are falling behind
+fn queue_message(meta: &Arc<SocketMetadata>, payload: Payload, size: usize) {
+    if in_stale_worker(meta) {
+        return;
+    }
+
+    meta.messages_pending.fetch_add(1, Ordering::Relaxed);
+    if meta.options.coalesce {
+        queue_coalesced(meta, payload, size);
+        return;
+    }
+
+    if meta.options.batch_messages {
+        queue_batched(meta, payload);
+        return;
+    }
+
+    if meta.options.max_bytes_per_tick.is_some() {
+        queue_throttled(meta, payload, size);
+        return;
+    }
+
+    let meta = Arc::clone(meta);
+    next_tick(move |l| {
+        meta.messages_pending.fetch_sub(1, Ordering::Relaxed);
+        if !is_closed() {
+            deliver_message(l, &meta, payload);
+        }
+    });
+}
+
+fn deliver_message(l: &lua::State, meta: &SocketMetadata, payload: Payload) {
+    if take_response(l, meta, &payload) {
+        return;
+    }
+
+    run_call(l, meta.id, "on_message", move |l, target, func| match payload {
+        Payload::Text(data) => func.call::<()>(l, (target, data, false)),
+        Payload::Bytes(data) => func.call::<()>(l, (target, l.create_string(&data), false)),
+        Payload::Json(value) => func.call::<()>(l, (target, json_table(l, &value), true)),
+    });
+}
+
+// with `max_bytes_per_tick`, messages wait in `inbound` and every tick delivers as many as fit
+// into the budget (at least one), whatever is left is picked up by the next tick
+fn queue_throttled(meta: &Arc<SocketMetadata>, payload: Payload, size: usize) {
+    let mut inbound = meta.inbound.lock().unwrap();
+    inbound.push_back((payload, size));
+    if inbound.len() == 1 {
+        schedule_drain(Arc::clone(meta));
+    }
+}
+
+fn schedule_drain(meta: Arc<SocketMetadata>) {
     next_tick(move |l| {
         if is_closed() {
             return;
         }
 
-        let Some(target) = get_target(id) else {
+        if drain_inbound(l, &meta, meta.options.max_bytes_per_tick) {
+            schedule_drain(meta);
+        }
+    });
+}
+
+// returns true if messages are still waiting
+fn drain_inbound(l: &lua::State, meta: &SocketMetadata, budget: Option<usize>) -> bool {
+    let mut delivered = 0;
+    loop {
+        let next = {
+            let mut inbound = meta.inbound.lock().unwrap();
+            match inbound.front() {
+                Some((_, size)) if delivered > 0 && budget.is_some_and(|budget| delivered + size > budget) => return true,
+                Some(_) => inbound.pop_front(),
+                None => return false,
+            }
+        };
+
+        let Some((payload, size)) = next else {
+            return false;
+        };
+
+        delivered += size.max(1);
+        meta.messages_pending.fetch_sub(1, Ordering::Relaxed);
+        deliver_message(l, meta, payload);
+    }
+}
+
+// like batching, everything received until the tick runs is handled together, but only the last
+// message for every key returned by `coalesce_key` is kept (messages without a key are all kept).
+// replies to requests are taken out first, the rest goes on like it would without `coalesce`
+fn queue_coalesced(meta: &Arc<SocketMetadata>, payload: Payload, size: usize) {
+    {
+        let mut coalesced = meta.coalesced.lock().unwrap();
+        coalesced.push((payload, size));
+        if coalesced.len() > 1 {
+            return;
+        }
+    }
+
+    let meta = Arc::clone(meta);
+    next_tick(move |l| {
+        let mut coalesced = std::mem::take(&mut *meta.coalesced.lock().unwrap());
+        meta.messages_pending.fetch_sub(coalesced.len() as u64, Ordering::Relaxed);
+        if is_closed() {
             return;
+        }
+
+        coalesced.retain(|(payload, _)| !take_response(l, &meta, payload));
+        let kept = coalesce(l, &meta, coalesced);
+
+        if meta.options.batch_messages {
+            deliver_batch(l, &meta, kept.into_iter().map(|(payload, _)| payload).collect());
+        } else if meta.options.max_bytes_per_tick.is_some() {
+            // counted again until `drain_inbound` delivers them
+            meta.messages_pending.fetch_add(kept.len() as u64, Ordering::Relaxed);
+            let was_empty = {
+                let mut inbound = meta.inbound.lock().unwrap();
+                let was_empty = inbound.is_empty();
+                inbound.extend(kept);
+                was_empty
+            };
+
+            // otherwise a drain is already scheduled
+            if was_empty && drain_inbound(l, &meta, meta.options.max_bytes_per_tick) {
+                schedule_drain(meta);
+            }
+        } else {
+            for (payload, _) in kept {
+                deliver_message(l, &meta, payload);
+            }
+        }
+    });
+}
+
+fn coalesce(l: &lua::State, meta: &SocketMetadata, messages: Vec<(Payload, usize)>) -> Vec<(Payload, usize)> {
+    let Some((target, _)) = get_entry(meta.id) else {
+        return Vec::new();
+    };
+
+    let Some(Ok(key_fn)) = find_callback(l, &target, meta, "coalesce_key") else {
+        return messages;
+    };
+
+    let mut latest: HashMap<String, usize> = HashMap::new();
+    let mut kept: Vec<Option<(Payload, usize)>> = Vec::with_capacity(messages.len());
+    for (payload, size) in messages {
+        let key = match &payload {
+            Payload::Text(data) => key_fn.call::<Option<lua::String>>(l, (target.clone(), data.as_str(), false)),
+            Payload::Bytes(data) => key_fn.call::<Option<lua::String>>(l, (target.clone(), l.create_string(data), false)),
+            Payload::Json(value) => key_fn.call::<Option<lua::String>>(l, (target.clone(), json_table(l, value), true)),
         };
 
-        let func = match target.get::<Function>(l, name) {
-            Ok(func) => func,
-            Err(_) => return,
+        let key = key.unwrap_or_else(|err| {
+            l.error_no_halt_with_stack(&err.to_string());
+            None
+        });
+
+        if let Some(key) = key
+            && let Some(previous) = latest.insert(key.to_string(), kept.len())
+        {
+            kept[previous] = None;
+        }
+
+        kept.push(Some((payload, size)));
+    }
+
+    kept.into_iter().flatten().collect()
+}
+
+// only the first message of a batch queues a tick, everything received until it runs goes with it
+fn queue_batched(meta: &Arc<SocketMetadata>, payload: Payload) {
+    {
+        let mut batch = meta.batch.lock().unwrap();
+        batch.push(payload);
+        if batch.len() > 1 {
+            return;
+        }
+    }
+
+    let meta = Arc::clone(meta);
+    next_tick(move |l| {
+        let batch = std::mem::take(&mut *meta.batch.lock().unwrap());
+        meta.messages_pending.fetch_sub(batch.len() as u64, Ordering::Relaxed);
+        if !is_closed() {
+            deliver_batch(l, &meta, batch);
+        }
+    });
+}
+
+fn deliver_batch(l: &lua::State, meta: &SocketMetadata, mut batch: Vec<Payload>) {
+    batch.retain(|payload| !take_response(l, meta, payload));
+    if batch.is_empty() {
+        return;
+    }
+
+    run_call(l, meta.id, "on_messages", move |l, target, func| {
+        let messages = l.create_table();
+        for (index, payload) in batch.iter().enumerate() {
+            match payload {
+                Payload::Text(data) => messages.raw_set(l, index + 1, data.as_str()),
+                Payload::Bytes(data) => messages.raw_set(l, index + 1, l.create_string(data)),
+                Payload::Json(value) => messages.raw_set(l, index + 1, json_table(l, value)),
+            }
+        }
+
+        func.call::<()>(l, (target, messages))
+    });
+}
+
+// the request fails right away if its message couldn't be sent, instead of waiting for the timeout
+pub fn add_request(meta: &Arc<SocketMetadata>, matcher: Function, callback: Function, timeout: Duration, confirmation: oneshot::Receiver<Result<(), String>>) {
+    let id = meta.next_request.fetch_add(1, Ordering::Relaxed);
+    meta.requests.lock().unwrap().push(PendingRequest { id, matcher, callback });
+
+    let meta = Arc::clone(meta);
+    tasks::spawn(async move {
+        let sleep = time::sleep(timeout);
+        tokio::pin!(sleep);
+
+        let error = tokio::select! {
+            _ = &mut sleep => "request timed out".to_string(),
+            confirmed = confirmation => match confirmed {
+                Ok(Ok(())) => {
+                    sleep.await;
+                    "request timed out".to_string()
+                }
+                Ok(Err(err)) => format!("send failed: {err}"),
+                Err(_) => "send failed: message was dropped before it was sent".to_string(),
+            },
+        };
+
+        next_tick(move |l| {
+            if is_closed() {
+                return;
+            }
+
+            if let Some(request) = meta.take_request(id)
+                && let Err(err) = request.callback.call::<()>(l, (None::<String>, error))
+            {
+                l.error_no_halt_with_stack(&err.to_string());
+            }
+        });
+    });
+}
+
+// the file is read one chunk at a time and every chunk waits for the previous one to be written,
+// so memory stays at a single chunk no matter how big the file is
+pub fn send_file(meta: &Arc<SocketMetadata>, sender: mpsc::UnboundedSender<SocketCommand>, path: PathBuf, chunk_size: usize) {
+    let id = meta.id;
+    tasks::spawn(async move {
+        if let Err(err) = stream_file(id, sender, &path, chunk_size).await {
+            queue_error(id, SocketError::non_fatal("file", format!("failed to send {}: {err}", path.display())));
+        }
+    });
+}
+
+async fn stream_file(id: Uuid, sender: mpsc::UnboundedSender<SocketCommand>, path: &Path, chunk_size: usize) -> Result<(), String> {
+    if path.is_absolute() || path.components().any(|component| matches!(component, Component::ParentDir | Component::Prefix(_))) {
+        return Err("path has to be relative and can't contain '..'".to_string());
+    }
+
+    // same sandbox as the file library's DATA path, symlinks are resolved so they can't lead out of it
+    let root = fs::canonicalize(FILE_ROOT).await.map_err(|err| format!("can't resolve {FILE_ROOT}: {err}"))?;
+    let path = fs::canonicalize(root.join(path)).await.map_err(|err| err.to_string())?;
+    if !path.starts_with(&root) {
+        return Err(format!("path has to be inside {FILE_ROOT}"));
+    }
+
+    let mut file = File::open(&path).await.map_err(|err| err.to_string())?;
+    let total = file.metadata().await.map_err(|err| err.to_string())?.len();
+    let mut sent: u64 = 0;
+    let mut chunk = vec![0; chunk_size];
+
+    loop {
+        let mut filled = 0;
+        while filled < chunk_size {
+            match file.read(&mut chunk[filled..]).await.map_err(|err| err.to_string())? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+
+        if filled == 0 {
+            return Ok(());
+        }
+
+        let (confirm, confirmation) = oneshot::channel();
+        sender
+            .send(SocketCommand::Send(Message::Binary(chunk[..filled].to_vec().into()), Some(confirm)))
+            .map_err(|_| "socket is closed".to_string())?;
+
+        match confirmation.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => return Err(format!("write failed: {err}")),
+            Err(_) => return Err("socket closed before the file was sent".to_string()),
+        }
+
+        sent += filled as u64;
+        queue_call(id, "on_progress", move |l, target, func| func.call::<()>(l, (target, sent, total)));
+    }
+}
+
+// gives the message to the first pending request whose matcher accepts it, matched messages
+// don't reach `on_message`
+fn take_response(l: &lua::State, meta: &SocketMetadata, payload: &Payload) -> bool {
+    let matchers: Vec<(u64, Function)> = meta
+        .requests
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|request| (request.id, request.matcher.clone()))
+        .collect();
+
+    if matchers.is_empty() {
+        return false;
+    }
+
+    let (string, table) = match payload {
+        Payload::Text(data) => (Some(l.create_string(data)), None),
+        Payload::Bytes(data) => (Some(l.create_string(data)), None),
+        Payload::Json(value) => (None, Some(json_table(l, value))),
+    };
+
+    for (id, matcher) in matchers {
+        let matched = match &table {
+            Some(table) => matcher.call::<bool>(l, table.clone()),
+            None => matcher.call::<bool>(l, string.clone()),
+        };
+
+        match matched {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(err) => {
+                l.error_no_halt_with_stack(&err.to_string());
+                continue;
+            }
+        }
+
+        // the matcher could have already resolved it (e.g. by failing the request from lua)
+        let Some(request) = meta.take_request(id) else {
+            continue;
+        };
+
+        let result = match &table {
+            Some(table) => request.callback.call::<()>(l, table.clone()),
+            None => request.callback.call::<()>(l, string.clone()),
         };
 
-        let result = match data {
-            Some(data) => func.call::<()>(l, (target.clone(), data)),
-            None => func.call::<()>(l, target.clone()),
+        if let Err(err) = result {
+            l.error_no_halt_with_stack(&err.to_string());
+        }
+
+        return true;
+    }
+
+    false
+}
+
+// `on_log` of the socket takes priority over the logger set with `tungstenite.set_logger`
+// with `structured_logs` enabled, on_log and on_error get this instead of the plain message
+fn structured_message(meta: &SocketMetadata, event: &str, level: &str, category: Option<&str>, code: Option<u16>, message: &str) -> String {
+    serde_json::json!({
+        "event": event,
+        "level": level,
+        "category": category,
+        "code": code,
+        "message": message,
+        "socket_id": meta.id.to_string(),
+        "url": meta.url(),
+        "timestamp": now_millis(),
+    })
+    .to_string()
+}
+
+fn queue_log(id: Uuid, level: &'static str, message: String) {
+    let generation = worker_generation();
+    next_tick(move |l| {
+        if is_closed() || is_stale(id, generation) {
+            return;
+        }
+
+        let Some((target, meta)) = get_entry(id) else {
+            return;
+        };
+
+        let message = if meta.options.structured_logs {
+            structured_message(&meta, "log", level, None, None, &message)
+        } else {
+            message
+        };
+
+        let result = match find_callback(l, &target, &meta, "on_log") {
+            Some(func) => func.and_then(|func| func.call::<()>(l, (target, level, message))),
+            None => {
+                let logger = LOGGER.lock().unwrap().clone();
+                match logger {
+                    Some(logger) => logger.call::<()>(l, (target, level, message)),
+                    None => Ok(()),
+                }
+            }
         };
 
         if let Err(err) = result {
@@ -78,115 +663,951 @@ fn queue_callback(id: Uuid, name: &'static str, data: Option<String>) {
     });
 }
 
-pub fn queue_disconnect(id: Uuid, reason: String) {
-    queue_callback(id, "on_disconnect", Some(reason));
+fn queue_send_failed(id: Uuid, reason: String) {
+    queue_callback(id, "on_send_failed", Some(reason));
+}
+
+// for sends rejected in lua, which also happens after the socket was closed and unregistered
+pub fn queue_send_failed_on(target: AnyUserData, meta: Arc<SocketMetadata>, reason: String) {
+    next_tick(move |l| {
+        if !is_closed() {
+            call_on(l, target, &meta, "on_send_failed", move |l, target, func| func.call::<()>(l, (target, reason)));
+        }
+    });
+}
+
+pub fn queue_state_change(id: Uuid, old: SocketState, new: SocketState) {
+    queue_call(id, "on_state_change", move |l, target, func| {
+        func.call::<()>(l, (target, old.as_str(), new.as_str()))
+    });
+}
+
+fn queue_error(id: Uuid, error: SocketError) {
+    let level = if error.fatal { "error" } else { "warn" };
+    queue_log(id, level, format!("{} error: {}", error.category, error.message));
+
+    let meta = get_entry(id).map(|(_, meta)| meta);
+    let message = match &meta {
+        Some(meta) if meta.options.structured_logs => {
+            structured_message(meta, "error", level, Some(error.category), error.code, &error.message)
+        }
+        _ => error.message,
+    };
+
+    queue_call(id, "on_error", move |l, target, func| {
+        // the disconnect this leads to doesn't go through `on_error` a second time
+        if error.fatal
+            && let Some(meta) = &meta
+        {
+            meta.error_reported.store(true, Ordering::Release);
+        }
+
+        func.call::<()>(l, (target, message, error.fatal, error.category, error.code))
+    });
+}
+
+fn queue_settle(meta: &Arc<SocketMetadata>, result: Result<(), String>) {
+    let meta = Arc::clone(meta);
+    let generation = worker_generation();
+    next_tick(move |l| {
+        if is_closed() || is_stale(meta.id, generation) {
+            return;
+        }
+
+        let handlers = meta.promise.lock().unwrap().settle(&result);
+        let Some(target) = get_target(meta.id) else {
+            return;
+        };
+
+        for handler in handlers {
+            let result = match &result {
+                Ok(()) => handler.call::<()>(l, target.clone()),
+                Err(err) => handler.call::<()>(l, err.clone()),
+            };
+
+            if let Err(err) = result {
+                l.error_no_halt_with_stack(&err.to_string());
+            }
+        }
+    });
 }
 
-fn notify_disconnect(meta: &Arc<SocketMetadata>, reason: impl Into<String>) {
-    meta.state.set(SocketState::Disconnected);
+fn connect_error(err: &tungstenite::Error) -> SocketError {
+    let tungstenite::Error::Http(response) = err else {
+        return SocketError::fatal("connect", err.to_string());
+    };
+
+    let status = response.status();
+    let message = match response.body().as_deref().map(String::from_utf8_lossy) {
+        Some(body) if !body.is_empty() => format!("http error {status}: {body}"),
+        _ => format!("http error {status}"),
+    };
+
+    SocketError {
+        category: "http",
+        message,
+        code: Some(status.as_u16()),
+        fatal: true,
+    }
+}
+
+// without `on_disconnect` or `on_close`, the disconnect is reported through `on_error` so it doesn't go
+// unnoticed, unless `on_error` already got the fatal error that caused it
+pub fn queue_disconnect(id: Uuid, info: DisconnectInfo) {
+    queue_log(id, "info", format!("disconnected ({}): {}", info.code, info.reason));
+
+    let generation = worker_generation();
+    next_tick(move |l| {
+        if is_closed() || is_stale(id, generation) {
+            return;
+        }
+
+        let Some((target, meta)) = get_entry(id) else {
+            return;
+        };
+
+        // messages held back by `max_bytes_per_tick` still arrive before the disconnect
+        drain_inbound(l, &meta, None);
+
+        // nothing is coming back for requests sent over this connection
+        let requests = std::mem::take(&mut *meta.requests.lock().unwrap());
+        for request in requests {
+            if let Err(err) = request.callback.call::<()>(l, (None::<String>, "disconnected")) {
+                l.error_no_halt_with_stack(&err.to_string());
+            }
+        }
+
+        let report = |result: lua::Result<()>| {
+            if let Err(err) = result {
+                l.error_no_halt_with_stack(&err.to_string());
+            }
+        };
+
+        let error_reported = meta.error_reported.swap(false, Ordering::AcqRel);
+        let on_close = find_callback(l, &target, &meta, "on_close");
+        let on_disconnect = find_callback(l, &target, &meta, "on_disconnect");
+
+        if on_close.is_none() && on_disconnect.is_none() {
+            if !error_reported
+                && let Some(func) = find_callback(l, &target, &meta, "on_error")
+            {
+                let message = format!("disconnected: {}", info.reason);
+                report(func.and_then(|func| func.call::<()>(l, (target, message, true, "disconnect", None::<u16>))));
+            }
+
+            return;
+        }
+
+        if let Some(func) = on_close {
+            report(func.and_then(|func| func.call::<()>(l, (target.clone(), info.to_table(l)))));
+        }
+
+        if let Some(func) = on_disconnect {
+            report(func.and_then(|func| func.call::<()>(l, (target, info.reason))));
+        }
+    });
+}
+
+// runs after the callbacks the worker queued before exiting, so they can still find the socket.
+// skipped if the socket was reopened in the meantime
+fn is_current(meta: &SocketMetadata, generation: u64) -> bool {
+    meta.generation.load(Ordering::Acquire) == generation
+}
+
+tokio::task_local! {
+    // the generation of the worker running on this task
+    static GENERATION: u64;
+}
+
+fn worker_generation() -> Option<u64> {
+    GENERATION.try_with(|generation| *generation).ok()
+}
+
+// callbacks queued by a worker remember its generation, whatever is still waiting once `open`
+// replaced that worker is dropped. anything queued from lua has no generation and always runs
+fn is_stale(id: Uuid, generation: Option<u64>) -> bool {
+    let Some(generation) = generation else {
+        return false;
+    };
+
+    SOCKETS.lock().unwrap().get(&id).is_some_and(|entry| !is_current(&entry.meta, generation))
+}
+
+// true inside a worker that `open` replaced, it can't touch the state of the new one anymore
+pub fn in_stale_worker(meta: &SocketMetadata) -> bool {
+    worker_generation().is_some_and(|generation| !is_current(meta, generation))
+}
+
+fn queue_release(meta: &Arc<SocketMetadata>, generation: u64) {
+    let meta = Arc::clone(meta);
+    next_tick(move |_| {
+        if is_current(&meta, generation) && meta.state.get() == SocketState::Disconnected {
+            unregister(meta.id);
+        }
+    });
+}
+
+// a worker that finishes closing after `open` started a new one has nothing left to report
+fn notify_disconnect(meta: &Arc<SocketMetadata>, generation: u64, info: DisconnectInfo) {
+    if !is_current(meta, generation) {
+        return;
+    }
+
+    meta.set_state(SocketState::Disconnected);
     if meta.mark_disconnect_notified() {
-        queue_disconnect(meta.id, reason.into());
+        queue_disconnect(meta.id, info);
+    }
+}
+
+pub fn check(url: String, callback: Function) {
+    tasks::spawn(async move {
+        let result = match connect_async(url.as_str()).await {
+            Ok((mut stream, response)) => {
+                let _ = stream.close(None).await;
+                Ok(response.status().to_string())
+            }
+            Err(err) => Err(err.to_string()),
+        };
+
+        next_tick(move |l| {
+            if is_closed() {
+                return;
+            }
+
+            let result = match result {
+                Ok(status) => callback.call::<()>(l, (true, status)),
+                Err(err) => callback.call::<()>(l, (false, err)),
+            };
+
+            if let Err(err) = result {
+                l.error_no_halt_with_stack(&err.to_string());
+            }
+        });
+    });
+}
+
+async fn sleep_after(start: Instant, timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => time::sleep_until(start + timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn build_request(meta: &SocketMetadata, url: &str) -> Result<Request, tungstenite::Error> {
+    let mut request = meta.options.apply_query(url).into_client_request()?;
+
+    if !matches!(request.uri().scheme_str(), Some("ws" | "wss")) {
+        return Err(tungstenite::Error::Url(UrlError::UnsupportedUrlScheme));
+    }
+
+    if request.uri().host().is_none_or(str::is_empty) {
+        return Err(tungstenite::Error::Url(UrlError::NoHostName));
+    }
+
+    if !meta.options.protocols.is_empty() {
+        let protocols = HeaderValue::from_str(&meta.options.protocols.join(", "))
+            .map_err(|err| tungstenite::Error::HttpFormat(http::Error::from(err)))?;
+        request.headers_mut().insert("Sec-WebSocket-Protocol", protocols);
+    }
+
+    if let Some(user_agent) = &meta.options.user_agent {
+        let user_agent = HeaderValue::from_str(user_agent)
+            .map_err(|err| tungstenite::Error::HttpFormat(http::Error::from(err)))?;
+        request.headers_mut().insert(header::USER_AGENT, user_agent);
+    }
+
+    for (name, value) in meta.headers.lock().unwrap().iter() {
+        request.headers_mut().insert(name, value.clone());
+    }
+
+    Ok(request)
+}
+
+#[cfg(feature = "rustls")]
+fn rustls_connector(meta: &SocketMetadata) -> Result<Option<Connector>, tungstenite::Error> {
+    let roots = rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| tungstenite::Error::Tls(err.into()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    config.alpn_protocols = meta.options.alpn.iter().map(|protocol| protocol.as_bytes().to_vec()).collect();
+    Ok(Some(Connector::Rustls(Arc::new(config))))
+}
+
+#[cfg(not(feature = "rustls"))]
+fn rustls_connector(_meta: &SocketMetadata) -> Result<Option<Connector>, tungstenite::Error> {
+    Err(tungstenite::Error::Url(UrlError::TlsFeatureNotEnabled))
+}
+
+fn build_connector(meta: &SocketMetadata) -> Result<Option<Connector>, tungstenite::Error> {
+    if meta.options.tls == TlsBackend::Rustls {
+        return rustls_connector(meta);
+    }
+
+    if meta.options.alpn.is_empty() {
+        return Ok(None);
+    }
+
+    let protocols: Vec<&str> = meta.options.alpn.iter().map(String::as_str).collect();
+    let connector = native_tls::TlsConnector::builder()
+        .request_alpns(&protocols)
+        .build()
+        .map_err(|err| tungstenite::Error::Tls(err.into()))?;
+
+    Ok(Some(Connector::NativeTls(connector)))
+}
+
+// checks everything about the connection that can fail before touching the network
+pub fn validate(meta: &SocketMetadata, url: &str) -> Result<(), tungstenite::Error> {
+    build_request(meta, url)?;
+    build_connector(meta)?;
+    Ok(())
+}
+
+fn target(request: &Request) -> Result<(String, u16), tungstenite::Error> {
+    let uri = request.uri();
+    let host = uri.host().ok_or(tungstenite::Error::Url(UrlError::NoHostName))?;
+    // ipv6 literals keep their brackets in the uri, which the resolver doesn't understand
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("wss") { 443 } else { 80 });
+    Ok((host.to_string(), port))
+}
+
+// ip literals go to the proxy as addresses, only actual host names are left for it to resolve
+fn socks5_target(host: &str, port: u16) -> TargetAddr<'_> {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => TargetAddr::Ip(SocketAddr::new(ip, port)),
+        Err(_) => TargetAddr::Domain(host.into(), port),
+    }
+}
+
+// the websocket handshake (and tls) runs over the tunnel as if it was a direct connection
+async fn socks5_connect(proxy: &Socks5Proxy, request: &Request) -> Result<TcpStream, SocketError> {
+    let (host, port) = target(request).map_err(|err| connect_error(&err))?;
+    let target = socks5_target(&host, port);
+    let stream = match &proxy.auth {
+        Some((username, password)) => Socks5Stream::connect_with_password(proxy.address.as_str(), target, username, password).await,
+        None => Socks5Stream::connect(proxy.address.as_str(), target).await,
+    };
+
+    stream
+        .map(Socks5Stream::into_inner)
+        .map_err(|err| SocketError::fatal("connect", format!("socks5 proxy: {err}")))
+}
+
+// resolving separately tells a broken resolver apart from a server that can't be reached
+async fn resolve_connect(request: &Request, timeout: Duration) -> Result<TcpStream, SocketError> {
+    let (host, port) = target(request).map_err(|err| connect_error(&err))?;
+    let addrs: Vec<SocketAddr> = match time::timeout(timeout, lookup_host((host.as_str(), port))).await {
+        Ok(Ok(addrs)) => addrs.collect(),
+        Ok(Err(err)) => return Err(SocketError::fatal("dns", format!("failed to resolve {host}: {err}"))),
+        Err(_) => return Err(SocketError::fatal("dns", format!("resolving {host} timed out"))),
+    };
+
+    if addrs.is_empty() {
+        return Err(SocketError::fatal("dns", format!("no addresses found for {host}")));
+    }
+
+    TcpStream::connect(addrs.as_slice())
+        .await
+        .map_err(|err| SocketError::fatal("connect", err.to_string()))
+}
+
+// `None` when there is no limit, changing the limit only affects connects that haven't started waiting yet
+static CONNECT_LIMIT: LazyLock<Mutex<Option<Arc<Semaphore>>>> = LazyLock::new(|| Mutex::new(None));
+
+pub fn set_max_concurrent_connects(max: usize) {
+    *CONNECT_LIMIT.lock().unwrap() = (max > 0).then(|| Arc::new(Semaphore::new(max)));
+}
+
+async fn connect_permit() -> Option<OwnedSemaphorePermit> {
+    let limit = CONNECT_LIMIT.lock().unwrap().clone()?;
+    limit.acquire_owned().await.ok()
+}
+
+async fn connect(meta: &SocketMetadata, url: &str) -> Result<(SocketStream, Response), SocketError> {
+    // held until the handshake finished or failed
+    let _permit = connect_permit().await;
+    let request = build_request(meta, url).map_err(|err| connect_error(&err))?;
+    let connector = build_connector(meta).map_err(|err| connect_error(&err))?;
+    let config = Some(meta.options.websocket_config());
+
+    let stream = match (&meta.options.socks5_proxy, meta.options.dns_timeout) {
+        (Some(proxy), _) => Some(socks5_connect(proxy, &request).await?),
+        (None, Some(timeout)) => Some(resolve_connect(&request, timeout).await?),
+        (None, None) => None,
+    };
+
+    let result = match stream {
+        Some(stream) => client_async_tls_with_config(request, stream, config, connector).await,
+        None => connect_async_tls_with_config(request, config, false, connector).await,
+    };
+
+    let (mut stream, response) = result.map_err(|err| connect_error(&err))?;
+
+    // tungstenite already rejects protocols that weren't offered, but not the server picking none
+    if meta.options.require_subprotocol && !meta.options.protocols.is_empty() && !response.headers().contains_key("Sec-WebSocket-Protocol") {
+        let _ = stream.close(None).await;
+        let offered = meta.options.protocols.join(", ");
+        return Err(SocketError::fatal("connect", format!("server didn't select any of the offered subprotocols ({offered})")));
+    }
+
+    Ok((stream, response))
+}
+
+fn tcp_stream(stream: &SocketStream) -> Option<&TcpStream> {
+    match stream.get_ref() {
+        MaybeTlsStream::Plain(stream) => Some(stream),
+        MaybeTlsStream::NativeTls(stream) => Some(stream.get_ref().get_ref().get_ref()),
+        #[cfg(feature = "rustls")]
+        MaybeTlsStream::Rustls(stream) => Some(stream.get_ref().0),
+        _ => None,
     }
 }
 
 pub fn spawn(id: Uuid, meta: Arc<SocketMetadata>) -> mpsc::UnboundedSender<SocketCommand> {
     let (sender, receiver) = mpsc::unbounded_channel();
-    tokio_tasks::spawn(handle_socket(receiver, id, meta));
+    let abort = CancellationToken::new();
+    *meta.abort.lock().unwrap() = abort.clone();
+    let generation = meta.generation.fetch_add(1, Ordering::AcqRel) + 1;
+    // an error that came after the last disconnect (e.g. `circuit_open`) doesn't belong to this one
+    meta.error_reported.store(false, Ordering::Release);
+
+    // aborting drops the worker wherever it is, taking the tcp stream down with it
+    tasks::spawn(GENERATION.scope(generation, async move {
+        tokio::select! {
+            _ = handle_socket(receiver, id, Arc::clone(&meta), generation) => {}
+            _ = abort.cancelled() => {
+                notify_disconnect(&meta, generation, DisconnectInfo::abnormal("aborted"));
+                queue_release(&meta, generation);
+            }
+        }
+    }));
 
     sender
 }
 
-async fn handle_socket(mut receiver: mpsc::UnboundedReceiver<SocketCommand>, id: Uuid, meta: Arc<SocketMetadata>) {
-    let connection = connect_async(meta.url.as_str()).await;
-    let (stream, _) = match connection {
-        Ok(parts) => parts,
-        Err(err) => {
-            notify_disconnect(&meta, format!("connect failed: {err}"));
-            queue_callback(id, "on_error", Some(err.to_string()));
-            return;
+enum SessionEnd {
+    // closed from lua (or the socket was garbage collected), never reconnected
+    Closed(DisconnectInfo),
+    // connection dropped or couldn't be established, `connected` tells which
+    Lost { info: DisconnectInfo, connected: bool },
+}
+
+async fn handle_socket(mut receiver: mpsc::UnboundedReceiver<SocketCommand>, id: Uuid, meta: Arc<SocketMetadata>, generation: u64) {
+    let mut attempt = 0;
+    let mut outage_started: Option<Instant> = None;
+    let mut is_reconnect = false;
+
+    loop {
+        let (info, connected) = match run_session(&mut receiver, id, &meta, is_reconnect).await {
+            SessionEnd::Closed(info) => {
+                notify_disconnect(&meta, generation, info);
+                break;
+            }
+            SessionEnd::Lost { info, connected } => (info, connected),
+        };
+
+        notify_disconnect(&meta, generation, info);
+        if !is_current(&meta, generation) || !meta.auto_reconnect.load(Ordering::Acquire) {
+            break;
+        }
+
+        // a failed first attempt doesn't make the next one a reconnect
+        if connected {
+            attempt = 0;
+            outage_started = None;
+            is_reconnect = true;
+        }
+
+        let outage_started = *outage_started.get_or_insert_with(Instant::now);
+        if meta.options.max_reconnect_duration.is_some_and(|max| outage_started.elapsed() >= max) {
+            queue_error(id, SocketError::fatal("circuit_open", "gave up reconnecting"));
+            break;
+        }
+
+        let delay = meta.options.backoff(attempt);
+        attempt += 1;
+
+        queue_log(id, "info", format!("reconnecting in {}ms (attempt {attempt})", delay.as_millis()));
+        meta.set_state(SocketState::Reconnecting);
+        if !wait_for_reconnect(&mut receiver, &meta, delay).await {
+            meta.set_state(SocketState::Disconnected);
+            break;
+        }
+
+        // `open` may have started a new worker while this one was waiting
+        if !is_current(&meta, generation) {
+            break;
+        }
+
+        meta.reset_for_open();
+    }
+
+    queue_release(&meta, generation);
+}
+
+// returns false if socket was closed (or auto reconnect was turned off) while waiting,
+// messages sent in the meantime are reported through on_send_failed, other commands are dropped
+async fn wait_for_reconnect(receiver: &mut mpsc::UnboundedReceiver<SocketCommand>, meta: &SocketMetadata, delay: Duration) -> bool {
+    let sleep = time::sleep(delay);
+    tokio::pin!(sleep);
+
+    loop {
+        tokio::select! {
+            _ = &mut sleep => return meta.auto_reconnect.load(Ordering::Acquire),
+            _ = meta.auto_reconnect_changed.notified() => {
+                if !meta.auto_reconnect.load(Ordering::Acquire) {
+                    return false;
+                }
+            }
+            maybe_command = receiver.recv() => match maybe_command {
+                None | Some(SocketCommand::Close(_) | SocketCommand::CloseNow) => return false,
+                Some(SocketCommand::Send(_, confirm)) => {
+                    if let Some(confirm) = confirm {
+                        let _ = confirm.send(Err("socket is reconnecting".to_string()));
+                    }
+
+                    queue_send_failed(meta.id, "socket is reconnecting".to_string());
+                }
+                Some(_) => {}
+            },
+        }
+    }
+}
+
+// a pooled connection can only be reused by a socket that would have made the same handshake
+fn pool_key(meta: &SocketMetadata, url: &str) -> String {
+    let options = &meta.options;
+    let mut headers: Vec<String> = meta
+        .headers
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, value)| format!("{name}: {}", String::from_utf8_lossy(value.as_bytes())))
+        .collect();
+    headers.sort();
+
+    let proxy = options.socks5_proxy.as_ref().map(|proxy| (&proxy.address, &proxy.auth));
+    format!(
+        "{}\n{headers:?}\n{:?}\n{:?}\n{}\n{:?}\n{proxy:?}",
+        options.apply_query(url),
+        options.protocols,
+        options.user_agent,
+        options.tls.as_str(),
+        options.alpn,
+    )
+}
+
+// sends the close frame and waits for the server to answer with its own, the connection is
+// dropped either way once the timeout runs out. returns whether the handshake finished
+async fn close_and_drain(
+    writer: &mut SplitSink<SocketStream, Message>,
+    reader: &mut SplitStream<SocketStream>,
+    frame: CloseFrame,
+    timeout: Duration,
+) -> Result<bool, tungstenite::Error> {
+    writer.send(Message::Close(Some(frame))).await?;
+
+    let drained = time::timeout(timeout, async {
+        while reader.next().await.is_some() {}
+    }).await;
+
+    Ok(drained.is_ok())
+}
+
+async fn run_session(receiver: &mut mpsc::UnboundedReceiver<SocketCommand>, id: Uuid, meta: &Arc<SocketMetadata>, is_reconnect: bool) -> SessionEnd {
+    // `set_url` takes effect on the next connection
+    let url = meta.url();
+    queue_log(id, "info", format!("connecting to {url}"));
+    queue_callback(id, "on_connecting", None);
+
+    let pool_key = pool_key(meta, &url);
+    let pooled = match meta.options.pool_ttl {
+        Some(_) => pool::take(&pool_key).await,
+        None => None,
+    };
+
+    // there was no handshake for a pooled connection, so there is no response either
+    let (mut stream, protocol, response) = match pooled {
+        Some(pooled) => (pooled.stream, pooled.protocol, None),
+        None => match connect(meta, &url).await {
+            Ok((stream, response)) => {
+                let protocol = response
+                    .headers()
+                    .get("Sec-WebSocket-Protocol")
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string);
+
+                (stream, protocol, Some((response.status().as_u16(), response.headers().clone())))
+            }
+            Err(err) => {
+                let reason = format!("connect failed: {}", err.message);
+                queue_settle(meta, Err(err.message.clone()));
+                queue_error(id, err);
+                return SessionEnd::Lost { info: DisconnectInfo::abnormal(reason), connected: false };
+            }
         }
     };
 
-    meta.state.set(SocketState::Connected);
-    queue_callback(id, "on_connect", None);
+    // closed and opened again while this was connecting, the new worker has its own connection
+    if in_stale_worker(meta) {
+        let _ = stream.close(None).await;
+        return SessionEnd::Closed(DisconnectInfo::abnormal("closed by user"));
+    }
+
+    *meta.protocol.lock().unwrap() = protocol;
+    *meta.response.lock().unwrap() = response;
+    *meta.local_addr.lock().unwrap() = tcp_stream(&stream).and_then(|stream| stream.local_addr().ok());
+
+    if let (Some(interval), Some(tcp)) = (meta.options.tcp_keepalive, tcp_stream(&stream)) {
+        let keepalive = TcpKeepalive::new().with_time(interval).with_interval(interval);
+        if let Err(err) = SockRef::from(tcp).set_tcp_keepalive(&keepalive) {
+            queue_error(id, SocketError::non_fatal("connect", format!("failed to enable tcp keepalive: {err}")));
+        }
+    }
+
+    meta.connected_at.store(now_millis(), Ordering::Release);
+    meta.missed_pongs.store(0, Ordering::Relaxed);
+    meta.set_state(SocketState::Connected);
+    if is_reconnect {
+        meta.reconnect_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    queue_log(id, "info", "connected".to_string());
+    queue_call(id, "on_connect", move |l, target, func| func.call::<()>(l, (target, is_reconnect)));
+    queue_settle(meta, Ok(()));
 
     let (mut writer, mut reader) = stream.split();
     let mut heartbeat = time::interval(HEARTBEAT_INTERVAL);
     heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
     heartbeat.tick().await;
+    let connected_at = Instant::now();
+    let mut last_activity = connected_at;
+    // unlike `last_activity`, control frames don't count here
+    let mut last_message = connected_at;
+    // heartbeat pings carry a counter, so their pongs can be told apart from ones for `ping`
+    let mut heartbeat_count: u64 = 0;
+    let mut heartbeat_sent: Option<Instant> = None;
 
-    let disconnect_reason = loop {
+    loop {
         tokio::select! {
             maybe_command = receiver.recv() => {
                 let Some(command) = maybe_command else {
-                    break "socket command channel closed".to_string();
+                    break SessionEnd::Closed(DisconnectInfo::abnormal("socket command channel closed"));
                 };
 
                 match command {
-                    SocketCommand::Send(data) => {
-                        if let Err(err) = writer.send(Message::Text(data.into())).await {
-                            queue_callback(id, "on_error", Some(err.to_string()));
-                            break format!("write failed: {err}");
+                    SocketCommand::Send(message, confirm) => {
+                        let size = message.len();
+                        let started = Instant::now();
+                        if let Err(err) = writer.send(message).await {
+                            if let Some(confirm) = confirm {
+                                let _ = confirm.send(Err(err.to_string()));
+                            }
+
+                            queue_send_failed(id, format!("write failed: {err}"));
+                            queue_error(id, SocketError::fatal("write", err.to_string()));
+                            break SessionEnd::Lost { info: DisconnectInfo::abnormal(format!("write failed: {err}")), connected: true };
+                        }
+
+                        if let Some(confirm) = confirm {
+                            let _ = confirm.send(Ok(()));
+                        }
+
+                        meta.touch_sent();
+                        meta.messages_sent.fetch_add(1, Ordering::Relaxed);
+                        if let Some(histogram) = &meta.histogram {
+                            histogram.record_sent(size);
+                        }
+
+                        // the write only completes once the frame made it into the os buffer,
+                        // so a slow write means the server isn't keeping up with us
+                        if meta.options.slow_write_threshold.is_some_and(|threshold| started.elapsed() >= threshold) {
+                            queue_callback(id, "on_slow", None);
+                        }
+                    }
+                    SocketCommand::Frame(frame) => {
+                        if let Err(err) = writer.send(Message::Frame(frame)).await {
+                            queue_error(id, SocketError::fatal("write", err.to_string()));
+                            break SessionEnd::Lost { info: DisconnectInfo::abnormal(format!("write failed: {err}")), connected: true };
+                        }
+
+                        meta.touch_sent();
+                    }
+                    SocketCommand::Ping(payload) => {
+                        if let Err(err) = writer.send(Message::Ping(payload.into())).await {
+                            queue_error(id, SocketError::fatal("write", err.to_string()));
+                            break SessionEnd::Lost { info: DisconnectInfo::abnormal(format!("ping failed: {err}")), connected: true };
+                        }
+
+                        meta.touch_sent();
+                    }
+                    SocketCommand::Pong(payload) => {
+                        if let Err(err) = writer.send(Message::Pong(payload.into())).await {
+                            queue_error(id, SocketError::fatal("write", err.to_string()));
+                            break SessionEnd::Lost { info: DisconnectInfo::abnormal(format!("pong failed: {err}")), connected: true };
                         }
+
+                        meta.touch_sent();
                     }
-                    SocketCommand::Close => {
-                        let frame = CloseFrame {
-                            code: tungstenite::protocol::frame::coding::CloseCode::Normal,
-                            reason: Utf8Bytes::from_static("closed by user"),
-                        };
-
-                        if let Err(err) = writer.send(Message::Close(Some(frame))).await {
-                            queue_callback(id, "on_error", Some(err.to_string()));
-                            break format!("close failed: {err}");
+                    SocketCommand::Close(frame) => {
+                        // only a plain `close()` pools the connection, a code or reason has to
+                        // actually reach the server. there is no close handshake, so it isn't clean
+                        if let Some(ttl) = meta.options.pool_ttl
+                            && frame.code == CloseCode::Normal
+                            && frame.reason.is_empty()
+                        {
+                            if let Ok(stream) = writer.reunite(reader) {
+                                let protocol = meta.protocol.lock().unwrap().clone();
+                                pool::park(pool_key.clone(), stream, protocol, ttl);
+                            }
+
+                            break SessionEnd::Closed(DisconnectInfo::new(CloseCode::Normal.into(), "closed by user (pooled)", false));
                         }
 
-                        break "closed by user".to_string();
+                        let code: u16 = frame.code.into();
+                        match close_and_drain(&mut writer, &mut reader, frame, meta.options.close_timeout).await {
+                            Ok(clean) => break SessionEnd::Closed(DisconnectInfo::new(code, "closed by user", clean)),
+                            Err(err) => {
+                                queue_error(id, SocketError::fatal("write", err.to_string()));
+                                break SessionEnd::Closed(DisconnectInfo::abnormal(format!("close failed: {err}")));
+                            }
+                        }
                     }
                     SocketCommand::CloseNow => {
                         let _ = writer.close().await;
-                        break "closed by user".to_string();
+                        break SessionEnd::Closed(DisconnectInfo::abnormal("closed by user"));
                     }
                 }
             }
             maybe_message = reader.next() => {
                 let Some(message) = maybe_message else {
-                    break "connection closed".to_string();
+                    break SessionEnd::Lost { info: DisconnectInfo::abnormal("connection closed"), connected: true };
                 };
 
+                if message.is_ok() {
+                    last_activity = Instant::now();
+                    meta.touch_received();
+                }
+
+                if matches!(message, Ok(Message::Text(_) | Message::Binary(_) | Message::Frame(_))) {
+                    last_message = Instant::now();
+                }
+
                 match message {
                     Ok(Message::Text(text)) => {
-                        queue_callback(id, "on_message", Some(text.to_string()));
+                        meta.record_received(text.len());
+                        queue_message(meta, Payload::text(&meta.options, text.to_string()), text.len());
                     }
                     Ok(Message::Binary(data)) => {
-                        queue_callback(id, "on_message", Some(String::from_utf8_lossy(&data).to_string()));
+                        meta.record_received(data.len());
+                        queue_message(meta, Payload::binary(&meta.options, data.to_vec()), data.len());
                     }
                     Ok(Message::Ping(data)) => {
+                        let payload = data.to_vec();
+                        queue_call(id, "on_ping", move |l, target, func| func.call::<()>(l, (target, l.create_string(&payload))));
+
                         if let Err(err) = writer.send(Message::Pong(data)).await {
-                            queue_callback(id, "on_error", Some(err.to_string()));
-                            break format!("pong failed: {err}");
+                            queue_error(id, SocketError::fatal("write", err.to_string()));
+                            break SessionEnd::Lost { info: DisconnectInfo::abnormal(format!("pong failed: {err}")), connected: true };
                         }
+
+                        meta.touch_sent();
                     }
                     Ok(Message::Close(frame)) => {
-                        break frame
+                        let code: u16 = frame.as_ref().map_or(CloseCode::Status.into(), |frame| frame.code.into());
+                        let reason = frame
                             .map(|frame| frame.reason.to_string())
                             .filter(|reason| !reason.is_empty())
                             .unwrap_or_else(|| "connection closed".to_string());
+
+                        // tungstenite queues the close reply while reading, flush it and
+                        // drain the stream until the server finishes the handshake
+                        let _ = writer.flush().await;
+                        let drained = time::timeout(meta.options.close_timeout, async {
+                            while reader.next().await.is_some() {}
+                        }).await;
+
+                        break SessionEnd::Lost { info: DisconnectInfo::new(code, reason, drained.is_ok()), connected: true };
                     }
                     Err(err) => {
-                        queue_callback(id, "on_error", Some(err.to_string()));
-                        break format!("read failed: {err}");
+                        queue_error(id, SocketError::fatal("read", err.to_string()));
+                        break SessionEnd::Lost { info: DisconnectInfo::abnormal(format!("read failed: {err}")), connected: true };
+                    }
+                    Ok(Message::Pong(data)) => {
+                        if *data == heartbeat_count.to_be_bytes()
+                            && let Some(sent) = heartbeat_sent.take()
+                        {
+                            meta.record_rtt(sent.elapsed());
+                            meta.missed_pongs.store(0, Ordering::Relaxed);
+                        }
                     }
                     _ => {}
                 }
             }
             _ = heartbeat.tick() => {
-                if let Err(err) = writer.send(Message::Ping(Vec::new().into())).await {
-                    queue_callback(id, "on_error", Some(err.to_string()));
-                    break format!("heartbeat failed: {err}");
+                // still waiting on the previous one
+                if heartbeat_sent.is_some() {
+                    meta.missed_pongs.fetch_add(1, Ordering::Relaxed);
+                }
+
+                heartbeat_count += 1;
+                heartbeat_sent = Some(Instant::now());
+                if let Err(err) = writer.send(Message::Ping(heartbeat_count.to_be_bytes().to_vec().into())).await {
+                    queue_error(id, SocketError::fatal("write", err.to_string()));
+                    break SessionEnd::Lost { info: DisconnectInfo::abnormal(format!("heartbeat failed: {err}")), connected: true };
                 }
+
+                meta.touch_sent();
+            }
+            _ = sleep_after(last_activity, meta.options.idle_timeout) => {
+                queue_error(id, SocketError::fatal("timeout", "no activity from server"));
+                let _ = writer.close().await;
+                break SessionEnd::Lost { info: DisconnectInfo::abnormal("idle timeout"), connected: true };
+            }
+            _ = sleep_after(last_message, meta.options.inactivity_timeout) => {
+                queue_error(id, SocketError::fatal("timeout", "no messages from server"));
+                let frame = CloseFrame {
+                    code: CloseCode::Normal,
+                    reason: Utf8Bytes::from_static("inactivity_timeout"),
+                };
+
+                let clean = close_and_drain(&mut writer, &mut reader, frame, meta.options.close_timeout).await.unwrap_or(false);
+                break SessionEnd::Lost { info: DisconnectInfo::new(CloseCode::Normal.into(), "inactivity timeout", clean), connected: true };
+            }
+            _ = sleep_after(connected_at, meta.options.max_lifetime) => {
+                let frame = CloseFrame {
+                    code: CloseCode::Normal,
+                    reason: Utf8Bytes::from_static("max_lifetime"),
+                };
+
+                let clean = close_and_drain(&mut writer, &mut reader, frame, meta.options.close_timeout).await.unwrap_or(false);
+                break SessionEnd::Lost { info: DisconnectInfo::new(CloseCode::Normal.into(), "max_lifetime", clean), connected: true };
             }
         }
-    };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    use super::*;
+    use crate::socket::options::SocketOptions;
+    use crate::socket::userdata::close_frame;
+
+    // accepts a single connection and sends every text and binary message back
+    async fn echo_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = accept_async(stream).await.unwrap();
+            while let Some(Ok(message)) = stream.next().await {
+                if message.is_text() || message.is_binary() {
+                    stream.send(message).await.unwrap();
+                }
+            }
+        });
+
+        url
+    }
+
+    // accepts a single connection and reports the close frame the client sent
+    async fn close_server() -> (String, oneshot::Receiver<Option<CloseFrame>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (report, received) = oneshot::channel();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = accept_async(stream).await.unwrap();
+            let mut frame = None;
+
+            // the close reply is sent while reading, so keep reading until the stream ends
+            while let Some(Ok(message)) = stream.next().await {
+                if let Message::Close(close) = message {
+                    frame = close;
+                }
+            }
+
+            let _ = report.send(frame);
+        });
+
+        (url, received)
+    }
+
+    async fn close_with(frame: CloseFrame) -> CloseFrame {
+        let (url, received) = close_server().await;
+        let (stream, _) = connect_async(url).await.unwrap();
+        let (mut writer, mut reader) = stream.split();
+
+        let clean = close_and_drain(&mut writer, &mut reader, frame, Duration::from_secs(5)).await.unwrap();
+        assert!(clean, "server didn't finish the close handshake");
+
+        received.await.unwrap().expect("server didn't receive a close frame")
+    }
 
-    notify_disconnect(&meta, disconnect_reason);
+    #[test]
+    fn target_strips_ipv6_brackets() {
+        let target_of = |url: &str| target(&url.into_client_request().unwrap()).unwrap();
+
+        assert_eq!(target_of("ws://[::1]:8080"), ("::1".to_string(), 8080));
+        assert_eq!(target_of("wss://[2001:db8::1]/path"), ("2001:db8::1".to_string(), 443));
+        assert_eq!(target_of("ws://127.0.0.1:8080"), ("127.0.0.1".to_string(), 8080));
+        assert_eq!(target_of("ws://example.com"), ("example.com".to_string(), 80));
+    }
+
+    #[test]
+    fn socks5_target_sends_ip_literals_as_addresses() {
+        assert_eq!(socks5_target("::1", 8080), TargetAddr::Ip("[::1]:8080".parse().unwrap()));
+        assert_eq!(socks5_target("127.0.0.1", 80), TargetAddr::Ip("127.0.0.1:80".parse().unwrap()));
+        assert_eq!(socks5_target("example.com", 443), TargetAddr::Domain("example.com".into(), 443));
+    }
+
+    #[tokio::test]
+    async fn echoes_over_loopback() {
+        let url = echo_server().await;
+        let meta = SocketMetadata::new(url.clone(), SocketOptions::default());
+        let (stream, response) = connect(&meta, &url).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::SWITCHING_PROTOCOLS);
+
+        let (mut writer, mut reader) = stream.split();
+        writer.send(Message::text("hello")).await.unwrap();
+        assert_eq!(reader.next().await.unwrap().unwrap(), Message::text("hello"));
+
+        writer.send(Message::binary(vec![0, 1, 2, 255])).await.unwrap();
+        assert_eq!(reader.next().await.unwrap().unwrap(), Message::binary(vec![0, 1, 2, 255]));
+
+        let clean = close_and_drain(&mut writer, &mut reader, close_frame(None, None).unwrap(), Duration::from_secs(5)).await.unwrap();
+        assert!(clean, "server didn't finish the close handshake");
+    }
+
+    #[tokio::test]
+    async fn close_reason_is_empty_by_default() {
+        let frame = close_with(close_frame(None, None).unwrap()).await;
+        assert_eq!(frame.code, CloseCode::Normal);
+        assert_eq!(frame.reason.as_str(), "");
+    }
+
+    #[tokio::test]
+    async fn close_sends_requested_code_and_reason() {
+        let frame = close_with(close_frame(Some(4000), Some(b"going away")).unwrap()).await;
+        assert_eq!(u16::from(frame.code), 4000);
+        assert_eq!(frame.reason.as_str(), "going away");
+    }
+
+    #[test]
+    fn close_frame_rejects_bad_input() {
+        assert!(close_frame(Some(1005), None).is_err());
+        assert!(close_frame(None, Some(&[0xff, 0xfe])).is_err());
+        assert!(close_frame(None, Some(&[b'a'; 124])).is_err());
+        assert!(close_frame(None, Some(&[b'a'; 123])).is_ok());
+    }
 }
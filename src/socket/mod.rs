@@ -1,14 +1,68 @@
+mod ffi;
 mod handler;
+mod options;
+mod pool;
+mod tasks;
 mod types;
 mod userdata;
 
-use gmodx::lua::{State, Table};
+use gmodx::lua::{self, Function, State, Table};
+use uuid::Uuid;
 
-use crate::socket::handler::shutdown_all;
+use crate::socket::handler::{check, get_target, set_logger, set_max_concurrent_connects, shutdown_all, stats};
+
+// writing to a connection the server already dropped raises SIGPIPE on unix, which kills the
+// process unless it's ignored. rust only ignores it for executables, not for a module loaded
+// into srcds, so do it here unless the server already installed its own handler. the write
+// then fails with a broken pipe error and the socket disconnects like with any other write error
+#[cfg(unix)]
+fn ignore_sigpipe() {
+    unsafe {
+        let previous = libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+        if previous != libc::SIG_DFL && previous != libc::SIG_ERR {
+            libc::signal(libc::SIGPIPE, previous);
+        }
+    }
+}
 
 pub fn on_gmod_open(state: &State, table: &Table) {
-    table.raw_set(state, "connect", state.create_function(|l: &State, url| {
-        crate::socket::types::Socket::new(l, url)
+    #[cfg(unix)]
+    ignore_sigpipe();
+
+    table.raw_set(state, "connect", state.create_function(|l: &State, url, options: Option<Table>| {
+        crate::socket::types::Socket::new(l, url, options)
+    }));
+
+    table.raw_set(state, "connect_async", state.create_function(|l: &State, url, options: Option<Table>| {
+        crate::socket::types::Socket::new(l, url, options)
+    }));
+
+    table.raw_set(state, "set_defaults", state.create_function(|l: &State, options: Option<Table>| {
+        crate::socket::options::set_defaults(l, options)
+    }));
+
+    table.raw_set(state, "set_compat", state.create_function(|_: &State, enabled: bool| {
+        crate::socket::types::set_compat(enabled)
+    }));
+
+    table.raw_set(state, "set_max_concurrent_connects", state.create_function(|_: &State, max: usize| {
+        set_max_concurrent_connects(max)
+    }));
+
+    table.raw_set(state, "set_logger", state.create_function(|_: &State, logger: Option<Function>| {
+        set_logger(logger)
+    }));
+
+    table.raw_set(state, "get", state.create_function(|_: &State, id: lua::String| {
+        Uuid::parse_str(&id.to_string()).ok().and_then(get_target)
+    }));
+
+    table.raw_set(state, "stats", state.create_function(|l: &State| {
+        stats(l)
+    }));
+
+    table.raw_set(state, "check", state.create_function(|_: &State, url: lua::String, callback: Function| {
+        check(url.to_string(), callback)
     }));
 }
 
@@ -0,0 +1,579 @@
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use gmodx::lua::{self, ObjectLike, State, Table};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+const DEFAULT_SLOW_WRITE_THRESHOLD: Duration = Duration::from_secs(1);
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// query parameters in the order they get appended, each key with all of its values
+pub type Query = Vec<(String, Vec<String>)>;
+
+// `None` when the key isn't set, `Some(None)` when it's set to 0 (disabled)
+fn get_duration(l: &State, table: &Table, key: &str) -> lua::Result<Option<Option<Duration>>> {
+    let millis = table.get::<Option<u64>>(l, key)?;
+    Ok(millis.map(|millis| Some(Duration::from_millis(millis)).filter(|duration| !duration.is_zero())))
+}
+
+fn get_strings(l: &State, table: &Table, key: &str) -> lua::Result<Option<Vec<String>>> {
+    let values = table.get::<Option<Vec<lua::String>>>(l, key)?;
+    Ok(values.map(|values| values.iter().map(|value| value.to_string()).collect()))
+}
+
+fn get_string(l: &State, table: &Table, key: &str) -> lua::Result<Option<String>> {
+    let value = table.get::<Option<lua::String>>(l, key)?;
+    Ok(value.map(|value| value.to_string()))
+}
+
+fn get_size(l: &State, table: &Table, key: &str) -> lua::Result<Option<usize>> {
+    let size = table.get::<Option<u64>>(l, key)?;
+    Ok(size.map(|size| size as usize))
+}
+
+fn millis(duration: Option<Duration>) -> u64 {
+    duration.map_or(0, |duration| duration.as_millis() as u64)
+}
+
+fn strings_table(l: &State, values: &[String]) -> Table {
+    let table = l.create_table();
+    for (index, value) in values.iter().enumerate() {
+        table.raw_set(l, index + 1, value.as_str());
+    }
+
+    table
+}
+
+// keys are sorted so the same table always produces the same url, array values repeat the key
+fn get_query(l: &State, table: &Table) -> lua::Result<Option<Query>> {
+    let Some(query) = table.get::<Option<Table>>(l, "query")? else {
+        return Ok(None);
+    };
+
+    let mut keys = query
+        .pairs::<lua::String, lua::Value>(l)
+        .map(|pair| pair.map(|(key, _)| key.to_string()))
+        .collect::<lua::Result<Vec<String>>>()?;
+    keys.sort();
+
+    let mut params = Vec::with_capacity(keys.len());
+    for key in keys {
+        let values = if let Ok(values) = query.get::<Vec<lua::String>>(l, key.as_str()) {
+            values.iter().map(|value| value.to_string()).collect()
+        } else if let Ok(value) = query.get::<lua::String>(l, key.as_str()) {
+            vec![value.to_string()]
+        } else if let Ok(value) = query.get::<bool>(l, key.as_str()) {
+            vec![value.to_string()]
+        } else {
+            return Err(lua::Error::Runtime(format!("invalid query parameter '{key}', expected a string, number, boolean or an array of them").into()));
+        };
+
+        params.push((key, values));
+    }
+
+    Ok(Some(params))
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    encoded
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsBackend {
+    Native,
+    Rustls,
+}
+
+impl TlsBackend {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TlsBackend::Native => "native",
+            TlsBackend::Rustls => "rustls",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Socks5Proxy {
+    pub address: String,
+    pub auth: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+    // accepts `host:port` or `user:password@host:port`, optionally prefixed with `socks5://`
+    fn parse(value: &str) -> lua::Result<Self> {
+        let value = value.strip_prefix("socks5://").unwrap_or(value);
+        let (auth, address) = match value.rsplit_once('@') {
+            Some((auth, address)) => {
+                let Some((username, password)) = auth.split_once(':') else {
+                    return Err(lua::Error::Runtime(format!("invalid socks5_proxy '{value}', expected 'user:password@host:port'").into()));
+                };
+
+                (Some((username.to_string(), password.to_string())), address)
+            }
+            None => (None, value),
+        };
+
+        if !address.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) {
+            return Err(lua::Error::Runtime(format!("invalid socks5_proxy '{value}', expected 'host:port'").into()));
+        }
+
+        Ok(Self { address: address.to_string(), auth })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    pub idle_timeout: Option<Duration>,
+    pub inactivity_timeout: Option<Duration>,
+    pub protocols: Vec<String>,
+    pub require_subprotocol: bool,
+    pub slow_write_threshold: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+    pub alpn: Vec<String>,
+    pub pool_ttl: Option<Duration>,
+    pub coroutine: bool,
+    pub read_buffer_size: Option<usize>,
+    pub write_buffer_size: Option<usize>,
+    pub max_write_buffer_size: Option<usize>,
+    pub max_message_size: Option<usize>,
+    pub max_frame_size: Option<usize>,
+    pub reconnect: bool,
+    pub reconnect_delay: Duration,
+    pub reconnect_max_delay: Duration,
+    pub max_reconnect_duration: Option<Duration>,
+    pub tcp_keepalive: Option<Duration>,
+    pub dns_timeout: Option<Duration>,
+    pub close_timeout: Duration,
+    pub user_agent: Option<String>,
+    pub max_send_size: Option<usize>,
+    pub auto_json: bool,
+    pub raw_frames: bool,
+    pub batch_messages: bool,
+    pub coalesce: bool,
+    pub max_bytes_per_tick: Option<usize>,
+    pub size_histogram: bool,
+    pub structured_logs: bool,
+    pub tls: TlsBackend,
+    pub socks5_proxy: Option<Socks5Proxy>,
+    pub query: Query,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            idle_timeout: None,
+            inactivity_timeout: None,
+            protocols: Vec::new(),
+            require_subprotocol: false,
+            slow_write_threshold: Some(DEFAULT_SLOW_WRITE_THRESHOLD),
+            max_lifetime: None,
+            alpn: Vec::new(),
+            pool_ttl: None,
+            coroutine: false,
+            read_buffer_size: None,
+            write_buffer_size: None,
+            max_write_buffer_size: None,
+            max_message_size: None,
+            max_frame_size: None,
+            reconnect: false,
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+            reconnect_max_delay: DEFAULT_RECONNECT_MAX_DELAY,
+            max_reconnect_duration: None,
+            tcp_keepalive: None,
+            dns_timeout: None,
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            user_agent: None,
+            max_send_size: None,
+            auto_json: false,
+            raw_frames: false,
+            batch_messages: false,
+            coalesce: false,
+            max_bytes_per_tick: None,
+            size_histogram: false,
+            structured_logs: false,
+            tls: TlsBackend::Native,
+            socks5_proxy: None,
+            query: Vec::new(),
+        }
+    }
+}
+
+static DEFAULTS: LazyLock<Mutex<SocketOptions>> = LazyLock::new(|| Mutex::new(SocketOptions::default()));
+
+pub fn set_defaults(l: &State, table: Option<Table>) -> lua::Result<()> {
+    let defaults = match table {
+        Some(table) => SocketOptions::default().merge(l, &table)?,
+        None => SocketOptions::default(),
+    };
+
+    *DEFAULTS.lock().unwrap() = defaults;
+    Ok(())
+}
+
+impl SocketOptions {
+    // per-call options are applied over whatever was set with `tungstenite.set_defaults`
+    pub fn from_table(l: &State, table: Option<&Table>) -> lua::Result<Self> {
+        let options = DEFAULTS.lock().unwrap().clone();
+        match table {
+            Some(table) => options.merge(l, table),
+            None => Ok(options),
+        }
+    }
+
+    pub fn merge(mut self, l: &State, table: &Table) -> lua::Result<Self> {
+        if let Some(idle_timeout) = get_duration(l, table, "idle_timeout_ms")? {
+            self.idle_timeout = idle_timeout;
+        }
+
+        if let Some(inactivity_timeout) = get_duration(l, table, "inactivity_timeout_ms")? {
+            self.inactivity_timeout = inactivity_timeout;
+        }
+
+        if let Some(protocols) = get_strings(l, table, "protocols")? {
+            self.protocols = protocols;
+        }
+
+        if let Some(require_subprotocol) = table.get::<Option<bool>>(l, "require_subprotocol")? {
+            self.require_subprotocol = require_subprotocol;
+        }
+
+        if let Some(slow_write_threshold) = get_duration(l, table, "slow_write_ms")? {
+            self.slow_write_threshold = slow_write_threshold;
+        }
+
+        if let Some(max_lifetime) = get_duration(l, table, "max_lifetime_ms")? {
+            self.max_lifetime = max_lifetime;
+        }
+
+        if let Some(alpn) = get_strings(l, table, "alpn")? {
+            self.alpn = alpn;
+        }
+
+        if let Some(pool_ttl) = get_duration(l, table, "pool_ttl_ms")? {
+            self.pool_ttl = pool_ttl;
+        }
+
+        if let Some(coroutine) = table.get::<Option<bool>>(l, "coroutine")? {
+            self.coroutine = coroutine;
+        }
+
+        if let Some(auto_json) = table.get::<Option<bool>>(l, "auto_json")? {
+            self.auto_json = auto_json;
+        }
+
+        if let Some(raw_frames) = table.get::<Option<bool>>(l, "raw_frames")? {
+            self.raw_frames = raw_frames;
+        }
+
+        if let Some(batch_messages) = table.get::<Option<bool>>(l, "batch_messages")? {
+            self.batch_messages = batch_messages;
+        }
+
+        if let Some(coalesce) = table.get::<Option<bool>>(l, "coalesce")? {
+            self.coalesce = coalesce;
+        }
+
+        if let Some(max_bytes_per_tick) = get_size(l, table, "max_bytes_per_tick")? {
+            self.max_bytes_per_tick = Some(max_bytes_per_tick).filter(|&size| size > 0);
+        }
+
+        if let Some(size_histogram) = table.get::<Option<bool>>(l, "size_histogram")? {
+            self.size_histogram = size_histogram;
+        }
+
+        if let Some(structured_logs) = table.get::<Option<bool>>(l, "structured_logs")? {
+            self.structured_logs = structured_logs;
+        }
+
+        if let Some(read_buffer_size) = get_size(l, table, "read_buffer_size")? {
+            self.read_buffer_size = Some(read_buffer_size);
+        }
+
+        if let Some(write_buffer_size) = get_size(l, table, "write_buffer_size")? {
+            self.write_buffer_size = Some(write_buffer_size);
+        }
+
+        if let Some(max_write_buffer_size) = get_size(l, table, "max_write_buffer_size")? {
+            self.max_write_buffer_size = Some(max_write_buffer_size);
+        }
+
+        if let Some(max_message_size) = get_size(l, table, "max_message_size")? {
+            self.max_message_size = Some(max_message_size);
+        }
+
+        if let Some(max_frame_size) = get_size(l, table, "max_frame_size")? {
+            self.max_frame_size = Some(max_frame_size);
+        }
+
+        if let Some(reconnect) = table.get::<Option<bool>>(l, "reconnect")? {
+            self.reconnect = reconnect;
+        }
+
+        if let Some(reconnect_delay) = get_duration(l, table, "reconnect_delay_ms")? {
+            self.reconnect_delay = reconnect_delay.unwrap_or_default();
+        }
+
+        if let Some(reconnect_max_delay) = get_duration(l, table, "reconnect_max_delay_ms")? {
+            self.reconnect_max_delay = reconnect_max_delay.unwrap_or_default();
+        }
+
+        if let Some(max_reconnect_duration) = get_duration(l, table, "max_reconnect_duration_ms")? {
+            self.max_reconnect_duration = max_reconnect_duration;
+        }
+
+        if let Some(tcp_keepalive) = get_duration(l, table, "tcp_keepalive_ms")? {
+            self.tcp_keepalive = tcp_keepalive;
+        }
+
+        if let Some(dns_timeout) = get_duration(l, table, "dns_timeout_ms")? {
+            self.dns_timeout = dns_timeout;
+        }
+
+        if let Some(close_timeout) = get_duration(l, table, "close_timeout_ms")? {
+            self.close_timeout = close_timeout.unwrap_or_default();
+        }
+
+        if let Some(user_agent) = get_string(l, table, "user_agent")? {
+            self.user_agent = Some(user_agent);
+        }
+
+        if let Some(max_send_size) = get_size(l, table, "max_send_size")? {
+            self.max_send_size = Some(max_send_size).filter(|&size| size > 0);
+        }
+
+        if let Some(tls) = get_string(l, table, "tls")? {
+            self.tls = match tls.as_str() {
+                "native" => TlsBackend::Native,
+                "rustls" => TlsBackend::Rustls,
+                _ => return Err(lua::Error::Runtime(format!("invalid tls backend '{tls}', expected 'native' or 'rustls'").into())),
+            };
+        }
+
+        if let Some(socks5_proxy) = get_string(l, table, "socks5_proxy")? {
+            self.socks5_proxy = if socks5_proxy.is_empty() { None } else { Some(Socks5Proxy::parse(&socks5_proxy)?) };
+        }
+
+        if let Some(query) = get_query(l, table)? {
+            self.query = query;
+        }
+
+        // tungstenite asserts this when the stream is created, which would panic the worker
+        let config = self.websocket_config();
+        if config.max_write_buffer_size <= config.write_buffer_size {
+            let (max, size) = (config.max_write_buffer_size, config.write_buffer_size);
+            return Err(lua::Error::Runtime(format!("max_write_buffer_size ({max}) must be bigger than write_buffer_size ({size})").into()));
+        }
+
+        Ok(self)
+    }
+
+    // appended to whatever query the url already has, before the fragment if there is one
+    pub fn apply_query(&self, url: &str) -> String {
+        if self.query.is_empty() {
+            return url.to_string();
+        }
+
+        let encoded: Vec<String> = self
+            .query
+            .iter()
+            .flat_map(|(key, values)| values.iter().map(move |value| format!("{}={}", percent_encode(key), percent_encode(value))))
+            .collect();
+
+        let (base, fragment) = match url.split_once('#') {
+            Some((base, fragment)) => (base, Some(fragment)),
+            None => (url, None),
+        };
+
+        let separator = match base.split_once('?') {
+            Some((_, "")) => "",
+            Some(_) => "&",
+            None => "?",
+        };
+
+        let mut url = format!("{base}{separator}{}", encoded.join("&"));
+        if let Some(fragment) = fragment {
+            url.push('#');
+            url.push_str(fragment);
+        }
+
+        url
+    }
+
+    // doubles the delay for every failed attempt in a row, up to `reconnect_max_delay`
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.reconnect_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.reconnect_max_delay.max(self.reconnect_delay))
+    }
+
+    pub fn websocket_config(&self) -> WebSocketConfig {
+        let mut config = WebSocketConfig::default();
+        if let Some(read_buffer_size) = self.read_buffer_size {
+            config = config.read_buffer_size(read_buffer_size);
+        }
+
+        if let Some(write_buffer_size) = self.write_buffer_size {
+            config = config.write_buffer_size(write_buffer_size);
+        }
+
+        if let Some(max_write_buffer_size) = self.max_write_buffer_size {
+            config = config.max_write_buffer_size(max_write_buffer_size);
+        }
+
+        // 0 lifts the limit
+        if let Some(max_message_size) = self.max_message_size {
+            config = config.max_message_size(Some(max_message_size).filter(|&size| size > 0));
+        }
+
+        if let Some(max_frame_size) = self.max_frame_size {
+            config = config.max_frame_size(Some(max_frame_size).filter(|&size| size > 0));
+        }
+
+        config
+    }
+
+    pub fn to_table(&self, l: &State) -> Table {
+        let table = l.create_table();
+        table.raw_set(l, "idle_timeout_ms", millis(self.idle_timeout));
+        table.raw_set(l, "inactivity_timeout_ms", millis(self.inactivity_timeout));
+        table.raw_set(l, "protocols", strings_table(l, &self.protocols));
+        table.raw_set(l, "require_subprotocol", self.require_subprotocol);
+        table.raw_set(l, "slow_write_ms", millis(self.slow_write_threshold));
+        table.raw_set(l, "max_lifetime_ms", millis(self.max_lifetime));
+        table.raw_set(l, "alpn", strings_table(l, &self.alpn));
+        table.raw_set(l, "tls", self.tls.as_str());
+        table.raw_set(l, "pool_ttl_ms", millis(self.pool_ttl));
+        table.raw_set(l, "coroutine", self.coroutine);
+        table.raw_set(l, "auto_json", self.auto_json);
+        table.raw_set(l, "raw_frames", self.raw_frames);
+        table.raw_set(l, "batch_messages", self.batch_messages);
+        table.raw_set(l, "coalesce", self.coalesce);
+        table.raw_set(l, "max_bytes_per_tick", self.max_bytes_per_tick.unwrap_or(0) as u64);
+        table.raw_set(l, "size_histogram", self.size_histogram);
+        table.raw_set(l, "structured_logs", self.structured_logs);
+
+        table.raw_set(l, "reconnect", self.reconnect);
+        table.raw_set(l, "reconnect_delay_ms", millis(Some(self.reconnect_delay)));
+        table.raw_set(l, "reconnect_max_delay_ms", millis(Some(self.reconnect_max_delay)));
+        table.raw_set(l, "max_reconnect_duration_ms", millis(self.max_reconnect_duration));
+
+        table.raw_set(l, "tcp_keepalive_ms", millis(self.tcp_keepalive));
+        table.raw_set(l, "dns_timeout_ms", millis(self.dns_timeout));
+        table.raw_set(l, "close_timeout_ms", millis(Some(self.close_timeout)));
+        table.raw_set(l, "max_send_size", self.max_send_size.unwrap_or(0) as u64);
+        // credentials are left out on purpose
+        if let Some(socks5_proxy) = &self.socks5_proxy {
+            table.raw_set(l, "socks5_proxy", socks5_proxy.address.as_str());
+        }
+
+        if let Some(user_agent) = &self.user_agent {
+            table.raw_set(l, "user_agent", user_agent.as_str());
+        }
+
+        let query = l.create_table();
+        for (key, values) in &self.query {
+            query.raw_set(l, key.as_str(), strings_table(l, values));
+        }
+        table.raw_set(l, "query", query);
+
+        let config = self.websocket_config();
+        table.raw_set(l, "read_buffer_size", config.read_buffer_size as u64);
+        table.raw_set(l, "write_buffer_size", config.write_buffer_size as u64);
+        table.raw_set(l, "max_write_buffer_size", config.max_write_buffer_size as u64);
+        table.raw_set(l, "max_message_size", config.max_message_size.unwrap_or(0) as u64);
+        table.raw_set(l, "max_frame_size", config.max_frame_size.unwrap_or(0) as u64);
+
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_query(query: &[(&str, &[&str])]) -> SocketOptions {
+        let query = query
+            .iter()
+            .map(|(key, values)| (key.to_string(), values.iter().map(|value| value.to_string()).collect()))
+            .collect();
+
+        SocketOptions { query, ..SocketOptions::default() }
+    }
+
+    #[test]
+    fn percent_encode_only_keeps_unreserved_characters() {
+        assert_eq!(percent_encode("aZ09-._~"), "aZ09-._~");
+        assert_eq!(percent_encode("a b&c=d/?#"), "a%20b%26c%3Dd%2F%3F%23");
+        assert_eq!(percent_encode("é"), "%C3%A9");
+    }
+
+    #[test]
+    fn apply_query_leaves_url_alone_without_query() {
+        assert_eq!(SocketOptions::default().apply_query("ws://host/path?a=1#top"), "ws://host/path?a=1#top");
+    }
+
+    #[test]
+    fn apply_query_appends_encoded_parameters() {
+        let options = with_query(&[("tags", &["x", "y"]), ("token", &["a b"])]);
+        let query = "tags=x&tags=y&token=a%20b";
+
+        assert_eq!(options.apply_query("ws://host/path"), format!("ws://host/path?{query}"));
+        assert_eq!(options.apply_query("ws://host/path?"), format!("ws://host/path?{query}"));
+        assert_eq!(options.apply_query("ws://host/path?a=1"), format!("ws://host/path?a=1&{query}"));
+        assert_eq!(options.apply_query("ws://host/path#top"), format!("ws://host/path?{query}#top"));
+    }
+
+    #[test]
+    fn socks5_proxy_parses_address_and_auth() {
+        let proxy = Socks5Proxy::parse("127.0.0.1:1080").unwrap();
+        assert_eq!(proxy.address, "127.0.0.1:1080");
+        assert_eq!(proxy.auth, None);
+
+        let proxy = Socks5Proxy::parse("socks5://user:p@ss@proxy.local:1080").unwrap();
+        assert_eq!(proxy.address, "proxy.local:1080");
+        assert_eq!(proxy.auth, Some(("user".to_string(), "p@ss".to_string())));
+    }
+
+    #[test]
+    fn socks5_proxy_rejects_invalid_values() {
+        for value in ["proxy.local", ":1080", "proxy.local:port", "proxy.local:70000", "user@proxy.local:1080"] {
+            assert!(Socks5Proxy::parse(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_delay() {
+        let options = SocketOptions {
+            reconnect_delay: Duration::from_millis(100),
+            reconnect_max_delay: Duration::from_millis(1000),
+            ..SocketOptions::default()
+        };
+
+        let delays: Vec<u128> = (0..6).map(|attempt| options.backoff(attempt).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(options.backoff(u32::MAX), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn backoff_never_drops_below_reconnect_delay() {
+        let options = SocketOptions {
+            reconnect_delay: Duration::from_secs(5),
+            reconnect_max_delay: Duration::from_secs(1),
+            ..SocketOptions::default()
+        };
+
+        assert_eq!(options.backoff(0), Duration::from_secs(5));
+        assert_eq!(options.backoff(3), Duration::from_secs(5));
+    }
+}
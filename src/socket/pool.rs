@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::oneshot;
+use tokio::time::{self, Duration};
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+use crate::socket::tasks;
+use crate::socket::types::SocketStream;
+
+pub struct PooledStream {
+    pub stream: SocketStream,
+    pub protocol: Option<String>,
+}
+
+// the stream itself stays with its keeper task, `take` asks for it through `claim`
+struct PoolEntry {
+    id: Uuid,
+    claim: oneshot::Sender<oneshot::Sender<PooledStream>>,
+}
+
+static POOL: LazyLock<Mutex<HashMap<String, Vec<PoolEntry>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn remove(key: &str, id: Uuid) {
+    let mut pool = POOL.lock().unwrap();
+    let Some(entries) = pool.get_mut(key) else {
+        return;
+    };
+
+    entries.retain(|entry| entry.id != id);
+    if entries.is_empty() {
+        pool.remove(key);
+    }
+}
+
+// while pooled the stream keeps being read, so pings are answered and whatever the server sends
+// is discarded instead of reaching the next socket. it's dropped once it expires or dies
+pub fn park(key: String, mut stream: SocketStream, protocol: Option<String>, ttl: Duration) {
+    let id = Uuid::new_v4();
+    let (claim, mut claimed) = oneshot::channel::<oneshot::Sender<PooledStream>>();
+    POOL.lock().unwrap().entry(key.clone()).or_default().push(PoolEntry { id, claim });
+
+    tasks::spawn(async move {
+        let expire = time::sleep(ttl);
+        tokio::pin!(expire);
+
+        loop {
+            tokio::select! {
+                reply = &mut claimed => {
+                    if let Ok(reply) = reply {
+                        let _ = reply.send(PooledStream { stream, protocol });
+                        return;
+                    }
+
+                    // the pool was cleared
+                    break;
+                }
+                message = stream.next() => match message {
+                    Some(Ok(Message::Ping(_))) => {
+                        // tungstenite queues the pong while reading
+                        if stream.flush().await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+                _ = &mut expire => break,
+            }
+        }
+
+        remove(&key, id);
+        let _ = stream.close(None).await;
+    });
+}
+
+// skips streams that died while waiting in the pool, `None` means a new connection is needed
+pub async fn take(key: &str) -> Option<PooledStream> {
+    loop {
+        let entry = {
+            let mut pool = POOL.lock().unwrap();
+            let entries = pool.get_mut(key)?;
+            let entry = entries.pop();
+            if entries.is_empty() {
+                pool.remove(key);
+            }
+
+            entry?
+        };
+
+        let (reply, replied) = oneshot::channel();
+        if entry.claim.send(reply).is_err() {
+            continue;
+        }
+
+        if let Ok(pooled) = replied.await {
+            return Some(pooled);
+        }
+    }
+}
+
+pub fn clear() {
+    POOL.lock().unwrap().clear();
+}
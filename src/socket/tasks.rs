@@ -0,0 +1,46 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gmodx::tokio_tasks;
+use tokio_util::sync::CancellationToken;
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+static SHUTDOWN: LazyLock<Mutex<CancellationToken>> = LazyLock::new(|| Mutex::new(CancellationToken::new()));
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+// decrements even if the runtime drops the task without finishing it
+struct RunningGuard;
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// every task of the module is spawned through here, so `shutdown` can stop all of them
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    let shutdown = SHUTDOWN.lock().unwrap().clone();
+    RUNNING.fetch_add(1, Ordering::AcqRel);
+
+    tokio_tasks::spawn(async move {
+        let _guard = RunningGuard;
+        tokio::select! {
+            _ = future => {}
+            _ = shutdown.cancelled() => {}
+        }
+    });
+}
+
+// cancels every task and waits for them to be dropped, so none of them is still running
+// module code once it's unloaded
+pub fn shutdown() {
+    std::mem::take(&mut *SHUTDOWN.lock().unwrap()).cancel();
+
+    let started = Instant::now();
+    while RUNNING.load(Ordering::Acquire) > 0 && started.elapsed() < SHUTDOWN_TIMEOUT {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
@@ -1,10 +1,18 @@
-use std::sync::{Arc, atomic::{AtomicBool, AtomicU8, Ordering}};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering}};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use gmodx::lua::{self, UserDataRef};
-use tokio::sync::mpsc;
+use gmodx::lua::{self, Function, ObjectLike, Table, UserDataRef};
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio::sync::{Notify, mpsc, oneshot};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite::{Message, http::HeaderMap, protocol::{CloseFrame, frame::Frame}}};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 use crate::socket::handler;
+use crate::socket::options::SocketOptions;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -13,6 +21,19 @@ pub enum SocketState {
     Connecting = 1,
     NotConnected = 2,
     Disconnected = 3,
+    Reconnecting = 4,
+}
+
+impl SocketState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SocketState::Connected => "connected",
+            SocketState::Connecting => "connecting",
+            SocketState::NotConnected => "not_connected",
+            SocketState::Disconnected => "disconnected",
+            SocketState::Reconnecting => "reconnecting",
+        }
+    }
 }
 
 pub struct AtomicState(AtomicU8);
@@ -22,50 +43,421 @@ impl AtomicState {
         Self(AtomicU8::new(state as u8))
     }
 
-    pub fn get(&self) -> SocketState {
-        match self.0.load(Ordering::Acquire) {
+    fn decode(value: u8) -> SocketState {
+        match value {
             0 => SocketState::Connected,
             1 => SocketState::Connecting,
             2 => SocketState::NotConnected,
             3 => SocketState::Disconnected,
+            4 => SocketState::Reconnecting,
             _ => unreachable!(),
         }
     }
 
-    pub fn set(&self, state: SocketState) {
-        self.0.store(state as u8, Ordering::Release);
+    pub fn get(&self) -> SocketState {
+        Self::decode(self.0.load(Ordering::Acquire))
+    }
+
+    // returns the previous state
+    pub fn swap(&self, state: SocketState) -> SocketState {
+        Self::decode(self.0.swap(state as u8, Ordering::AcqRel))
+    }
+}
+
+pub const CALLBACKS: &[&str] = &[
+    "on_connecting",
+    "on_state_change",
+    "on_connect",
+    "on_message",
+    "on_messages",
+    "on_error",
+    "on_disconnect",
+    "on_close",
+    "on_ping",
+    "on_slow",
+    "on_send_failed",
+    "on_progress",
+    "coalesce_key",
+    "on_log",
+];
+
+// set with `tungstenite.set_compat`, sockets keep the value they were created with
+static COMPAT: AtomicBool = AtomicBool::new(true);
+
+pub fn set_compat(enabled: bool) {
+    COMPAT.store(enabled, Ordering::Release);
+}
+
+// gwsockets callback names, only used when the snake_case one isn't set
+pub const GWSOCKETS_CALLBACKS: &[(&str, &str)] = &[
+    ("on_connect", "onConnected"),
+    ("on_message", "onMessage"),
+    ("on_error", "onError"),
+    ("on_disconnect", "onDisconnected"),
+];
+
+pub fn gwsockets_callback(name: &str) -> Option<&'static str> {
+    GWSOCKETS_CALLBACKS
+        .iter()
+        .find(|(callback, _)| *callback == name)
+        .map(|(_, alias)| *alias)
+}
+
+pub type SocketStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone)]
+pub struct SocketError {
+    pub category: &'static str,
+    pub message: String,
+    pub code: Option<u16>,
+    pub fatal: bool,
+}
+
+impl SocketError {
+    pub fn fatal(category: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            category,
+            message: message.into(),
+            code: None,
+            fatal: true,
+        }
+    }
+
+    pub fn non_fatal(category: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            category,
+            message: message.into(),
+            code: None,
+            fatal: false,
+        }
     }
 }
 
+const CLOSE_ABNORMAL: u16 = 1006;
+
+#[derive(Debug, Clone)]
+pub struct DisconnectInfo {
+    pub reason: String,
+    pub code: u16,
+    pub clean: bool,
+}
+
+impl DisconnectInfo {
+    pub fn new(code: u16, reason: impl Into<String>, clean: bool) -> Self {
+        Self {
+            reason: reason.into(),
+            code,
+            clean,
+        }
+    }
+
+    // connection went away without a close frame
+    pub fn abnormal(reason: impl Into<String>) -> Self {
+        Self::new(CLOSE_ABNORMAL, reason, false)
+    }
+
+    pub fn to_table(&self, l: &lua::State) -> Table {
+        let table = l.create_table();
+        table.raw_set(l, "code", self.code);
+        table.raw_set(l, "reason", self.reason.as_str());
+        table.raw_set(l, "was_clean", self.clean);
+
+        table
+    }
+}
+
+// reports whether a message made it out, dropped without an answer if the message never did
+pub type SendConfirmation = oneshot::Sender<Result<(), String>>;
+
 #[derive(Debug)]
 pub enum SocketCommand {
-    Send(String),
-    Close,
+    Send(Message, Option<SendConfirmation>),
+    Frame(Frame),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(CloseFrame),
     CloseNow,
 }
 
+pub enum Payload {
+    Text(String),
+    Bytes(Vec<u8>),
+    Json(Value),
+}
+
+impl Payload {
+    // with `auto_json`, anything that looks like a json object or array is decoded here,
+    // off the main thread. if it doesn't parse it's delivered as is
+    pub fn text(options: &SocketOptions, text: String) -> Self {
+        if options.raw_frames {
+            return Payload::Bytes(text.into_bytes());
+        }
+
+        if !options.auto_json || !text.trim_start().starts_with(['{', '[']) {
+            return Payload::Text(text);
+        }
+
+        match serde_json::from_str(&text) {
+            Ok(value) => Payload::Json(value),
+            Err(_) => Payload::Text(text),
+        }
+    }
+
+    pub fn binary(options: &SocketOptions, data: Vec<u8>) -> Self {
+        if options.raw_frames {
+            Payload::Bytes(data)
+        } else {
+            Self::text(options, String::from_utf8_lossy(&data).into_owned())
+        }
+    }
+}
+
+// upper bound (in bytes) of every bucket, anything bigger goes into the last one
+const HISTOGRAM_BUCKETS: [usize; 8] = [64, 256, 1024, 4096, 16384, 65536, 262144, 1048576];
+
+#[derive(Default)]
+pub struct SizeHistogram {
+    sent: [AtomicU64; HISTOGRAM_BUCKETS.len() + 1],
+    received: [AtomicU64; HISTOGRAM_BUCKETS.len() + 1],
+}
+
+impl SizeHistogram {
+    fn bucket(size: usize) -> usize {
+        HISTOGRAM_BUCKETS
+            .iter()
+            .position(|&bound| size <= bound)
+            .unwrap_or(HISTOGRAM_BUCKETS.len())
+    }
+
+    pub fn record_sent(&self, size: usize) {
+        self.sent[Self::bucket(size)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, size: usize) {
+        self.received[Self::bucket(size)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn buckets_table(l: &lua::State, counts: &[AtomicU64]) -> Table {
+        let table = l.create_table();
+        for (index, count) in counts.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            match HISTOGRAM_BUCKETS.get(index) {
+                Some(bound) => table.raw_set(l, bound.to_string().as_str(), count),
+                None => table.raw_set(l, "inf", count),
+            }
+        }
+
+        table
+    }
+
+    pub fn to_table(&self, l: &lua::State) -> Table {
+        let table = l.create_table();
+        table.raw_set(l, "sent", Self::buckets_table(l, &self.sent));
+        table.raw_set(l, "received", Self::buckets_table(l, &self.received));
+
+        table
+    }
+}
+
+pub struct PendingRequest {
+    pub id: u64,
+    pub matcher: Function,
+    pub callback: Function,
+}
+
+#[derive(Default)]
+pub struct ConnectPromise {
+    pub settled: Option<Result<(), String>>,
+    pub on_connect: Vec<Function>,
+    pub on_error: Vec<Function>,
+}
+
+impl ConnectPromise {
+    // returns the handlers that should be called for the given result
+    pub fn settle(&mut self, result: &Result<(), String>) -> Vec<Function> {
+        self.settled = Some(result.clone());
+        let on_connect = std::mem::take(&mut self.on_connect);
+        let on_error = std::mem::take(&mut self.on_error);
+
+        match result {
+            Ok(()) => on_connect,
+            Err(_) => on_error,
+        }
+    }
+}
+
 pub struct SocketMetadata {
     pub id: Uuid,
     pub state: AtomicState,
-    pub url: String,
+    url: Mutex<String>,
+    pub options: SocketOptions,
+    pub compat: bool,
+    pub headers: Mutex<HeaderMap>,
+    pub protocol: Mutex<Option<String>>,
+    pub response: Mutex<Option<(u16, HeaderMap)>>,
+    pub local_addr: Mutex<Option<SocketAddr>>,
+    pub last_received: AtomicU64,
+    pub last_sent: AtomicU64,
+    pub connected_at: AtomicU64,
+    pub rtt: AtomicU64,
+    pub rtt_average: AtomicU64,
+    pub missed_pongs: AtomicU64,
+    pub messages_received: AtomicU64,
+    pub messages_sent: AtomicU64,
+    pub reconnect_count: AtomicU64,
+    pub auto_reconnect: AtomicBool,
+    pub auto_reconnect_changed: Notify,
+    pub histogram: Option<SizeHistogram>,
+    pub messages_pending: AtomicU64,
+    pub batch: Mutex<Vec<Payload>>,
+    pub coalesced: Mutex<Vec<(Payload, usize)>>,
+    pub inbound: Mutex<VecDeque<(Payload, usize)>>,
+    pub requests: Mutex<Vec<PendingRequest>>,
+    pub next_request: AtomicU64,
+    pub promise: Mutex<ConnectPromise>,
+    pub abort: Mutex<CancellationToken>,
+    // bumped for every worker, so one that's still closing can't touch the socket after `open`
+    pub generation: AtomicU64,
+    misnamed_warned: Mutex<Vec<&'static str>>,
     disconnect_notified: AtomicBool,
+    // set once `on_error` was called with a fatal error, until the disconnect it caused is reported
+    pub error_reported: AtomicBool,
 }
 
 impl SocketMetadata {
-    pub fn new(url: String) -> Self {
+    pub fn new(url: String, options: SocketOptions) -> Self {
+        let histogram = options.size_histogram.then(SizeHistogram::default);
+        let auto_reconnect = AtomicBool::new(options.reconnect);
         Self {
             id: Uuid::new_v4(),
             state: AtomicState::new(SocketState::Connecting),
-            url,
+            url: Mutex::new(url),
+            options,
+            compat: COMPAT.load(Ordering::Acquire),
+            headers: Mutex::new(HeaderMap::new()),
+            protocol: Mutex::new(None),
+            response: Mutex::new(None),
+            local_addr: Mutex::new(None),
+            last_received: AtomicU64::new(0),
+            last_sent: AtomicU64::new(0),
+            connected_at: AtomicU64::new(0),
+            rtt: AtomicU64::new(0),
+            rtt_average: AtomicU64::new(0),
+            missed_pongs: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            reconnect_count: AtomicU64::new(0),
+            auto_reconnect,
+            auto_reconnect_changed: Notify::new(),
+            histogram,
+            messages_pending: AtomicU64::new(0),
+            batch: Mutex::new(Vec::new()),
+            coalesced: Mutex::new(Vec::new()),
+            inbound: Mutex::new(VecDeque::new()),
+            requests: Mutex::new(Vec::new()),
+            next_request: AtomicU64::new(0),
+            promise: Mutex::new(ConnectPromise::default()),
+            abort: Mutex::new(CancellationToken::new()),
+            generation: AtomicU64::new(0),
+            misnamed_warned: Mutex::new(Vec::new()),
             disconnect_notified: AtomicBool::new(false),
+            error_reported: AtomicBool::new(false),
         }
     }
 
+    // every state change goes through here so `on_state_change` sees all of them. a worker that
+    // `open` replaced is ignored, the state belongs to the new one
+    pub fn set_state(&self, state: SocketState) {
+        if handler::in_stale_worker(self) {
+            return;
+        }
+
+        let old = self.state.swap(state);
+        if old != state {
+            handler::queue_state_change(self.id, old, state);
+        }
+    }
+
+    pub fn url(&self) -> String {
+        self.url.lock().unwrap().clone()
+    }
+
+    pub fn set_url(&self, url: String) {
+        *self.url.lock().unwrap() = url;
+    }
+
     pub fn reset_for_open(&self) {
-        self.state.set(SocketState::Connecting);
+        self.set_state(SocketState::Connecting);
+        *self.protocol.lock().unwrap() = None;
+        *self.response.lock().unwrap() = None;
+        *self.local_addr.lock().unwrap() = None;
+        *self.promise.lock().unwrap() = ConnectPromise::default();
         self.disconnect_notified.store(false, Ordering::Release);
     }
 
+    pub fn touch_received(&self) {
+        self.last_received.store(now_millis(), Ordering::Release);
+    }
+
+    pub fn record_received(&self, size: usize) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        if let Some(histogram) = &self.histogram {
+            histogram.record_received(size);
+        }
+    }
+
+    // in microseconds, the average is weighted towards recent samples
+    pub fn record_rtt(&self, rtt: Duration) {
+        let rtt = (rtt.as_micros() as u64).max(1);
+        let average = self.rtt_average.load(Ordering::Relaxed);
+        let average = if average == 0 { rtt } else { (average * 7 + rtt) / 8 };
+
+        self.rtt.store(rtt, Ordering::Relaxed);
+        self.rtt_average.store(average, Ordering::Relaxed);
+    }
+
+    // 1 with an average rtt up to 50ms, falling to 0 at a second, halved for every heartbeat
+    // in a row that went unanswered. 0 while not connected
+    pub fn health(&self) -> f64 {
+        if self.state.get() != SocketState::Connected {
+            return 0.0;
+        }
+
+        let average = self.rtt_average.load(Ordering::Relaxed) as f64 / 1000.0;
+        let rtt_score = if average == 0.0 { 1.0 } else { (1.0 - (average - 50.0) / 950.0).clamp(0.0, 1.0) };
+        let missed = self.missed_pongs.load(Ordering::Relaxed).min(16) as i32;
+
+        rtt_score * 0.5f64.powi(missed)
+    }
+
+    pub fn touch_sent(&self) {
+        self.last_sent.store(now_millis(), Ordering::Release);
+    }
+
+    // true only the first time, so misnamed callbacks are reported once per socket
+    pub fn mark_misnamed_warned(&self, name: &'static str) -> bool {
+        let mut warned = self.misnamed_warned.lock().unwrap();
+        if warned.contains(&name) {
+            return false;
+        }
+
+        warned.push(name);
+        true
+    }
+
+    pub fn take_request(&self, id: u64) -> Option<PendingRequest> {
+        let mut requests = self.requests.lock().unwrap();
+        let index = requests.iter().position(|request| request.id == id)?;
+        Some(requests.remove(index))
+    }
+
     pub fn mark_disconnect_notified(&self) -> bool {
         !self.disconnect_notified.swap(true, Ordering::AcqRel)
     }
@@ -77,8 +469,12 @@ pub struct Socket {
 }
 
 impl Socket {
-    pub fn new(l: &lua::State, url: lua::String) -> lua::Result<UserDataRef<Socket>> {
-        let meta = Arc::new(SocketMetadata::new(url.to_string()));
+    pub fn new(l: &lua::State, url: lua::String, options: Option<Table>) -> lua::Result<UserDataRef<Socket>> {
+        let table = options;
+        let options = SocketOptions::from_table(l, table.as_ref())?;
+        let url = url.to_string();
+        let meta = Arc::new(SocketMetadata::new(url.clone(), options));
+        handler::validate(&meta, &url).map_err(|err| lua::Error::Runtime(format!("invalid connection ({err})").into()))?;
         let (sender, _) = mpsc::unbounded_channel();
 
         let ud = l.create_userdata(Self {
@@ -86,7 +482,15 @@ impl Socket {
             sender,
         });
 
-        handler::register(meta.id, ud.as_any().clone());
+        // callbacks passed along with options are set before the worker starts
+        if let Some(table) = &table {
+            for &name in CALLBACKS {
+                if let Some(func) = table.get::<Option<Function>>(l, name)? {
+                    ud.as_any().set(l, name, func)?;
+                }
+            }
+        }
+
         Self::spawn_for_userdata(&ud)?;
         Ok(ud)
     }
@@ -94,6 +498,7 @@ impl Socket {
     pub fn spawn_for_userdata(ud: &UserDataRef<Socket>) -> lua::Result<()> {
         let mut socket = ud.borrow_mut();
         socket.meta.reset_for_open();
+        handler::register(socket.meta.id, ud.as_any().clone(), Arc::clone(&socket.meta));
         let sender = handler::spawn(socket.meta.id, Arc::clone(&socket.meta));
         handler::set_sender(socket.meta.id, sender.clone());
         socket.sender = sender;
@@ -110,10 +515,10 @@ impl Socket {
         Ok(true)
     }
 
-    pub fn notify_disconnect_now(meta: Arc<SocketMetadata>, reason: String) {
-        meta.state.set(SocketState::Disconnected);
+    pub fn notify_disconnect_now(meta: Arc<SocketMetadata>, info: DisconnectInfo) {
+        meta.set_state(SocketState::Disconnected);
         if meta.mark_disconnect_notified() {
-            handler::queue_disconnect(meta.id, reason);
+            handler::queue_disconnect(meta.id, info);
         }
     }
 }
@@ -130,3 +535,18 @@ impl std::fmt::Display for Socket {
         write!(f, "tungstenite ({})", self.meta.id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_include_their_upper_bound() {
+        assert_eq!(SizeHistogram::bucket(0), 0);
+        assert_eq!(SizeHistogram::bucket(64), 0);
+        assert_eq!(SizeHistogram::bucket(65), 1);
+        assert_eq!(SizeHistogram::bucket(1_048_576), HISTOGRAM_BUCKETS.len() - 1);
+        assert_eq!(SizeHistogram::bucket(1_048_577), HISTOGRAM_BUCKETS.len());
+        assert_eq!(SizeHistogram::bucket(usize::MAX), HISTOGRAM_BUCKETS.len());
+    }
+}
@@ -1,25 +1,224 @@
-use gmodx::lua::{self, Methods, State, UserData, UserDataRef};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
-use crate::socket::types::{Socket, SocketCommand, SocketState};
+use gmodx::lua::{self, Function, Methods, ObjectLike, State, Table, UserData, UserDataRef};
+use tokio::sync::oneshot;
+use tokio_tungstenite::tungstenite::{Message, http::{HeaderName, HeaderValue}, protocol::{CloseFrame, frame::{Frame, FrameHeader, coding::{CloseCode, Control, Data, OpCode}}}};
+
+use crate::socket::handler;
+use crate::socket::types::{CALLBACKS, DisconnectInfo, GWSOCKETS_CALLBACKS, Socket, SocketCommand, SocketState, now_millis};
+
+const MAX_CONTROL_PAYLOAD: usize = 125;
+const MAX_CLOSE_REASON: usize = MAX_CONTROL_PAYLOAD - 2;
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+// raised to the caller and also reported through on_send_failed, for code that doesn't check
+fn send_failed(this: &UserDataRef<Socket>, socket: &Socket, reason: String) -> lua::Error {
+    handler::queue_send_failed_on(this.as_any().clone(), Arc::clone(&socket.meta), reason.clone());
+    lua::Error::Runtime(format!("send failed: {reason}").into())
+}
+
+fn check_send_size(this: &UserDataRef<Socket>, socket: &Socket, size: usize) -> lua::Result<()> {
+    match socket.meta.options.max_send_size {
+        Some(max_send_size) if size > max_send_size => {
+            Err(send_failed(this, socket, format!("message is {size} bytes, max_send_size is {max_send_size}")))
+        }
+        _ => Ok(()),
+    }
+}
 
 fn send(_l: &State, this: UserDataRef<Socket>, data: lua::String) -> lua::Result<()> {
     let socket = this.borrow();
+    let data = data.to_string();
+    check_send_size(&this, &socket, data.len())?;
+
+    socket
+        .sender
+        .send(SocketCommand::Send(Message::Text(data.into()), None))
+        .map_err(|err| send_failed(&this, &socket, err.to_string()))?;
+
+    Ok(())
+}
+
+fn send_binary(_l: &State, this: UserDataRef<Socket>, data: lua::String) -> lua::Result<()> {
+    let socket = this.borrow();
+    let data = data.as_bytes().to_vec();
+    check_send_size(&this, &socket, data.len())?;
+
+    socket
+        .sender
+        .send(SocketCommand::Send(Message::Binary(data.into()), None))
+        .map_err(|err| send_failed(&this, &socket, err.to_string()))?;
+
+    Ok(())
+}
+
+fn request(_l: &State, this: UserDataRef<Socket>, data: lua::String, matcher: Function, callback: Function, timeout_ms: Option<u64>) -> lua::Result<()> {
+    let socket = this.borrow();
+    let data = data.to_string();
+    check_send_size(&this, &socket, data.len())?;
 
+    let (confirm, confirmation) = oneshot::channel();
     socket
         .sender
-        .send(SocketCommand::Send(data.to_string()))
+        .send(SocketCommand::Send(Message::Text(data.into()), Some(confirm)))
+        .map_err(|err| send_failed(&this, &socket, err.to_string()))?;
+
+    let timeout = Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_REQUEST_TIMEOUT_MS));
+    handler::add_request(&socket.meta, matcher, callback, timeout, confirmation);
+    Ok(())
+}
+
+fn send_file(_l: &State, this: UserDataRef<Socket>, path: lua::String, chunk_size: Option<usize>) -> lua::Result<()> {
+    let socket = this.borrow();
+    let chunk_size = chunk_size.unwrap_or(DEFAULT_FILE_CHUNK_SIZE);
+    if chunk_size == 0 {
+        return Err(lua::Error::Runtime("send failed: chunk size can't be 0".into()));
+    }
+    check_send_size(&this, &socket, chunk_size)?;
+
+    handler::send_file(&socket.meta, socket.sender.clone(), PathBuf::from(path.to_string()), chunk_size);
+    Ok(())
+}
+
+// text frames have to be valid utf-8, anything else would violate the protocol
+fn send_auto(l: &State, this: UserDataRef<Socket>, data: lua::String) -> lua::Result<()> {
+    if std::str::from_utf8(data.as_bytes()).is_ok() {
+        send(l, this, data)
+    } else {
+        send_binary(l, this, data)
+    }
+}
+
+// the method table is shared by all sockets, so with compat off the gwsockets methods are
+// still there but refuse to run
+fn check_compat(socket: &Socket, alias: &str, name: &str) -> lua::Result<()> {
+    if socket.meta.compat {
+        return Ok(());
+    }
+
+    Err(lua::Error::Runtime(format!("'{alias}' is a gwsockets alias and compatibility is disabled, use '{name}' instead").into()))
+}
+
+// gwsockets' `write` takes a second argument to send data as binary
+fn write(l: &State, this: UserDataRef<Socket>, data: lua::String, binary: Option<bool>) -> lua::Result<()> {
+    check_compat(&this.borrow(), "write", "send")?;
+    if binary.unwrap_or(false) {
+        send_binary(l, this, data)
+    } else {
+        send(l, this, data)
+    }
+}
+
+// `Frame::message` only takes data opcodes, so the header is built by hand and everything
+// tungstenite would normally check is checked here
+fn build_frame(opcode: u8, payload: Vec<u8>, fin: bool) -> Result<Frame, String> {
+    // opcodes are 4 bits and tungstenite panics on anything bigger
+    if opcode > 15 {
+        return Err(format!("opcode {opcode} is out of range (0-15)"));
+    }
+
+    let opcode = OpCode::from(opcode);
+    match opcode {
+        OpCode::Data(Data::Reserved(_)) | OpCode::Control(Control::Reserved(_)) => {
+            return Err("reserved opcode".to_string());
+        }
+        OpCode::Control(_) if !fin => {
+            return Err("control frames can't be fragmented".to_string());
+        }
+        OpCode::Control(_) if payload.len() > MAX_CONTROL_PAYLOAD => {
+            return Err(format!("control frame payload can't exceed {MAX_CONTROL_PAYLOAD} bytes"));
+        }
+        // a close payload is either empty or starts with a 2 byte code
+        OpCode::Control(Control::Close) if payload.len() == 1 => {
+            return Err("close frame payload can't be a single byte".to_string());
+        }
+        _ => {}
+    }
+
+    let header = FrameHeader { is_final: fin, opcode, ..FrameHeader::default() };
+    Ok(Frame::from_payload(header, payload.into()))
+}
+
+fn send_frame(_: &State, this: UserDataRef<Socket>, opcode: u8, payload: Option<lua::String>, fin: Option<bool>) -> lua::Result<()> {
+    let payload = payload.map(|payload| payload.as_bytes().to_vec()).unwrap_or_default();
+    let frame = build_frame(opcode, payload, fin.unwrap_or(true))
+        .map_err(|err| lua::Error::Runtime(format!("send failed: {err}").into()))?;
+
+    this.borrow()
+        .sender
+        .send(SocketCommand::Frame(frame))
+        .map_err(|err| lua::Error::Runtime(format!("send failed: {err}").into()))?;
+
+    Ok(())
+}
+
+fn control_payload(kind: &str, payload: Option<lua::String>) -> lua::Result<Vec<u8>> {
+    let payload = payload.map(|payload| payload.as_bytes().to_vec()).unwrap_or_default();
+    if payload.len() > MAX_CONTROL_PAYLOAD {
+        return Err(lua::Error::Runtime(format!("send failed: {kind} payload can't exceed {MAX_CONTROL_PAYLOAD} bytes").into()));
+    }
+
+    Ok(payload)
+}
+
+fn ping(_: &State, this: UserDataRef<Socket>, payload: Option<lua::String>) -> lua::Result<()> {
+    let payload = control_payload("ping", payload)?;
+
+    this.borrow()
+        .sender
+        .send(SocketCommand::Ping(payload))
+        .map_err(|err| lua::Error::Runtime(format!("send failed: {err}").into()))?;
+
+    Ok(())
+}
+
+fn pong(_: &State, this: UserDataRef<Socket>, payload: Option<lua::String>) -> lua::Result<()> {
+    let payload = control_payload("pong", payload)?;
+
+    this.borrow()
+        .sender
+        .send(SocketCommand::Pong(payload))
         .map_err(|err| lua::Error::Runtime(format!("send failed: {err}").into()))?;
 
     Ok(())
 }
 
-fn close(_l: &State, this: UserDataRef<Socket>) -> lua::Result<()> {
+// the reason is left empty unless one is given
+pub fn close_frame(code: Option<u16>, reason: Option<&[u8]>) -> Result<CloseFrame, String> {
+    let code = code.map_or(CloseCode::Normal, CloseCode::from);
+    if !code.is_allowed() {
+        return Err(format!("close code {} isn't allowed", u16::from(code)));
+    }
+
+    let reason = match reason {
+        Some(reason) => String::from_utf8(reason.to_vec()).map_err(|err| format!("reason isn't valid utf-8: {err}"))?,
+        None => String::new(),
+    };
+    if reason.len() > MAX_CLOSE_REASON {
+        return Err(format!("reason can't exceed {MAX_CLOSE_REASON} bytes"));
+    }
+
+    Ok(CloseFrame { code, reason: reason.into() })
+}
+
+fn close(_l: &State, this: UserDataRef<Socket>, code: Option<u16>, reason: Option<lua::String>) -> lua::Result<()> {
+    if this.borrow().meta.state.get() == SocketState::Disconnected {
+        return Ok(());
+    }
+
+    let frame = close_frame(code, reason.as_ref().map(|reason| reason.as_bytes()))
+        .map_err(|err| lua::Error::Runtime(format!("failed to close connection ({err})").into()))?;
+
     let socket = this.borrow();
-    socket.meta.state.set(SocketState::Disconnected);
+    socket.meta.set_state(SocketState::Disconnected);
 
     socket
         .sender
-        .send(SocketCommand::Close)
+        .send(SocketCommand::Close(frame))
         .map_err(|err| lua::Error::Runtime(format!("failed to close connection ({err})").into()))?;
 
     Ok(())
@@ -33,22 +232,323 @@ fn close_now(_: &State, this: UserDataRef<Socket>) -> lua::Result<()> {
         .send(SocketCommand::CloseNow)
         .map_err(|err| lua::Error::Runtime(format!("failed to close connection ({err})").into()))?;
 
-    Socket::notify_disconnect_now(socket.meta.clone(), "closed by user".to_string());
+    Socket::notify_disconnect_now(socket.meta.clone(), DisconnectInfo::abnormal("closed by user"));
     Ok(())
 }
 
+fn close_now_alias(l: &State, this: UserDataRef<Socket>) -> lua::Result<()> {
+    check_compat(&this.borrow(), "closeNow", "close_now")?;
+    close_now(l, this)
+}
+
+fn abort(_: &State, this: UserDataRef<Socket>) {
+    this.borrow().meta.abort.lock().unwrap().cancel();
+}
+
 fn open(l: &State, this: UserDataRef<Socket>) -> lua::Result<bool> {
     Socket::reopen(l, &this)
 }
 
+fn set_url(_: &State, this: UserDataRef<Socket>, url: lua::String) -> lua::Result<()> {
+    let socket = this.borrow();
+    let url = url.to_string();
+    handler::validate(&socket.meta, &url).map_err(|err| lua::Error::Runtime(format!("invalid url ({err})").into()))?;
+
+    socket.meta.set_url(url);
+    Ok(())
+}
+
+fn set_header(_: &State, this: UserDataRef<Socket>, name: lua::String, value: lua::String) -> lua::Result<()> {
+    let name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|err| lua::Error::Runtime(format!("invalid header name ({err})").into()))?;
+    let value = HeaderValue::from_bytes(value.as_bytes())
+        .map_err(|err| lua::Error::Runtime(format!("invalid header value ({err})").into()))?;
+
+    this.borrow().meta.headers.lock().unwrap().insert(name, value);
+    Ok(())
+}
+
+fn set_header_alias(l: &State, this: UserDataRef<Socket>, name: lua::String, value: lua::String) -> lua::Result<()> {
+    check_compat(&this.borrow(), "setHeader", "set_header")?;
+    set_header(l, this, name, value)
+}
+
+fn next(l: &State, this: UserDataRef<Socket>, handler: Function) -> lua::Result<UserDataRef<Socket>> {
+    let settled = {
+        let socket = this.borrow();
+        let mut promise = socket.meta.promise.lock().unwrap();
+        match promise.settled {
+            Some(Ok(())) => true,
+            Some(Err(_)) => false,
+            None => {
+                promise.on_connect.push(handler.clone());
+                false
+            }
+        }
+    };
+
+    if settled {
+        handler.call::<()>(l, this.as_any().clone())?;
+    }
+
+    Ok(this)
+}
+
+fn catch(l: &State, this: UserDataRef<Socket>, handler: Function) -> lua::Result<UserDataRef<Socket>> {
+    let error = {
+        let socket = this.borrow();
+        let mut promise = socket.meta.promise.lock().unwrap();
+        match &promise.settled {
+            Some(Err(err)) => Some(err.clone()),
+            Some(Ok(())) => None,
+            None => {
+                promise.on_error.push(handler.clone());
+                None
+            }
+        }
+    };
+
+    if let Some(err) = error {
+        handler.call::<()>(l, err)?;
+    }
+
+    Ok(this)
+}
+
+fn get_state(_: &State, this: UserDataRef<Socket>) -> &'static str {
+    this.borrow().meta.state.get().as_str()
+}
+
+fn get_id(_: &State, this: UserDataRef<Socket>) -> String {
+    this.borrow().meta.id.to_string()
+}
+
+// the handle is just the id, it can't keep the socket alive or outlive it
+fn get_send_handle(_: &State, this: UserDataRef<Socket>) -> String {
+    this.borrow().meta.id.to_string()
+}
+
+fn get_protocol(_: &State, this: UserDataRef<Socket>) -> Option<String> {
+    this.borrow().meta.protocol.lock().unwrap().clone()
+}
+
+// headers sent more than once keep every value, in the order they were received
+fn get_response_headers(l: &State, this: UserDataRef<Socket>) -> Option<Table> {
+    let socket = this.borrow();
+    let response = socket.meta.response.lock().unwrap();
+    let (status, headers) = response.as_ref()?;
+
+    let values = l.create_table();
+    for name in headers.keys() {
+        let list = l.create_table();
+        for (index, value) in headers.get_all(name).iter().enumerate() {
+            list.raw_set(l, index + 1, String::from_utf8_lossy(value.as_bytes()).as_ref());
+        }
+        values.raw_set(l, name.as_str(), list);
+    }
+
+    let table = l.create_table();
+    table.raw_set(l, "status", *status);
+    table.raw_set(l, "headers", values);
+    Some(table)
+}
+
+fn get_local_address(_: &State, this: UserDataRef<Socket>) -> (Option<String>, Option<u16>) {
+    let local_addr = *this.borrow().meta.local_addr.lock().unwrap();
+    (local_addr.map(|addr| addr.ip().to_string()), local_addr.map(|addr| addr.port()))
+}
+
+fn load_timestamp(timestamp: &AtomicU64) -> Option<u64> {
+    Some(timestamp.load(Ordering::Acquire)).filter(|&millis| millis > 0)
+}
+
+fn get_last_received(_: &State, this: UserDataRef<Socket>) -> Option<u64> {
+    load_timestamp(&this.borrow().meta.last_received)
+}
+
+fn get_last_sent(_: &State, this: UserDataRef<Socket>) -> Option<u64> {
+    load_timestamp(&this.borrow().meta.last_sent)
+}
+
+fn get_last_activity(_: &State, this: UserDataRef<Socket>) -> Option<u64> {
+    let socket = this.borrow();
+    load_timestamp(&socket.meta.last_received).max(load_timestamp(&socket.meta.last_sent))
+}
+
+fn get_connected_at(_: &State, this: UserDataRef<Socket>) -> Option<u64> {
+    load_timestamp(&this.borrow().meta.connected_at)
+}
+
+fn get_uptime(_: &State, this: UserDataRef<Socket>) -> Option<f64> {
+    let socket = this.borrow();
+    if socket.meta.state.get() != SocketState::Connected {
+        return None;
+    }
+
+    let connected_at = load_timestamp(&socket.meta.connected_at)?;
+    Some(now_millis().saturating_sub(connected_at) as f64 / 1000.0)
+}
+
+fn get_rtt(_: &State, this: UserDataRef<Socket>) -> (Option<f64>, Option<f64>) {
+    let socket = this.borrow();
+    let millis = |rtt: &AtomicU64| Some(rtt.load(Ordering::Relaxed)).filter(|&rtt| rtt > 0).map(|rtt| rtt as f64 / 1000.0);
+    (millis(&socket.meta.rtt), millis(&socket.meta.rtt_average))
+}
+
+fn get_health(_: &State, this: UserDataRef<Socket>) -> (f64, &'static str) {
+    let health = this.borrow().meta.health();
+    let grade = match health {
+        health if health >= 0.75 => "good",
+        health if health >= 0.4 => "fair",
+        health if health > 0.0 => "poor",
+        _ => "dead",
+    };
+
+    (health, grade)
+}
+
+fn get_send_count(_: &State, this: UserDataRef<Socket>) -> u64 {
+    this.borrow().meta.messages_sent.load(Ordering::Relaxed)
+}
+
+fn get_receive_count(_: &State, this: UserDataRef<Socket>) -> u64 {
+    this.borrow().meta.messages_received.load(Ordering::Relaxed)
+}
+
+fn get_inbound_queue_depth(_: &State, this: UserDataRef<Socket>) -> u64 {
+    this.borrow().meta.messages_pending.load(Ordering::Relaxed)
+}
+
+fn get_stats(l: &State, this: UserDataRef<Socket>) -> Table {
+    let socket = this.borrow();
+    let stats = l.create_table();
+    stats.raw_set(l, "messages_sent", socket.meta.messages_sent.load(Ordering::Relaxed));
+    stats.raw_set(l, "messages_received", socket.meta.messages_received.load(Ordering::Relaxed));
+    stats.raw_set(l, "reconnect_count", socket.meta.reconnect_count.load(Ordering::Relaxed));
+
+    stats
+}
+
+fn get_size_histogram(l: &State, this: UserDataRef<Socket>) -> Option<Table> {
+    this.borrow().meta.histogram.as_ref().map(|histogram| histogram.to_table(l))
+}
+
+fn set_auto_reconnect(_: &State, this: UserDataRef<Socket>, enabled: bool) {
+    let socket = this.borrow();
+    socket.meta.auto_reconnect.store(enabled, Ordering::Release);
+    socket.meta.auto_reconnect_changed.notify_waiters();
+}
+
+fn get_config(l: &State, this: UserDataRef<Socket>) -> Table {
+    let socket = this.borrow();
+    let config = socket.meta.options.to_table(l);
+    config.raw_set(l, "reconnect", socket.meta.auto_reconnect.load(Ordering::Acquire));
+
+    config
+}
+
+// callbacks missing from the table are removed, gwsockets names included unless compat is off
+fn set_callbacks(l: &State, this: UserDataRef<Socket>, callbacks: Table) -> lua::Result<()> {
+    let compat = this.borrow().meta.compat;
+    let aliases = GWSOCKETS_CALLBACKS.iter().filter(|_| compat).map(|(_, alias)| alias);
+    for &name in CALLBACKS.iter().chain(aliases) {
+        let func = callbacks.get::<Option<Function>>(l, name)?;
+        this.as_any().set(l, name, func)?;
+    }
+
+    Ok(())
+}
+
+fn has_listener(l: &State, this: UserDataRef<Socket>, event: lua::String) -> lua::Result<bool> {
+    Ok(this.as_any().get::<Option<Function>>(l, event.to_string().as_str())?.is_some())
+}
+
+fn get_listeners(l: &State, this: UserDataRef<Socket>) -> lua::Result<Table> {
+    let listeners = l.create_table();
+    let mut index = 0;
+
+    let compat = this.borrow().meta.compat;
+    let aliases = GWSOCKETS_CALLBACKS.iter().filter(|_| compat).map(|(_, alias)| alias);
+    for &name in CALLBACKS.iter().chain(aliases) {
+        if this.as_any().get::<Option<Function>>(l, name)?.is_some() {
+            index += 1;
+            listeners.raw_set(l, index, name);
+        }
+    }
+
+    Ok(listeners)
+}
+
 impl UserData for Socket {
     fn methods(m: &mut Methods) {
         m.add(c"send", send);
-        m.add(c"write", send);
+        m.add(c"send_binary", send_binary);
+        m.add(c"send_auto", send_auto);
+        m.add(c"sendAuto", send_auto);
+        m.add(c"write", write);
+        m.add(c"request", request);
+        m.add(c"send_file", send_file);
+        m.add(c"sendFile", send_file);
+        m.add(c"send_frame", send_frame);
+        m.add(c"ping", ping);
+        m.add(c"pong", pong);
         m.add(c"close", close);
         m.add(c"close_now", close_now);
-        m.add(c"closeNow", close_now);
+        m.add(c"closeNow", close_now_alias);
+        m.add(c"abort", abort);
         m.add(c"open", open);
+        m.add(c"set_url", set_url);
+        m.add(c"setURL", set_url);
+        m.add(c"set_header", set_header);
+        m.add(c"setHeader", set_header_alias);
+        m.add(c"next", next);
+        m.add(c"catch", catch);
+        m.add(c"get_state", get_state);
+        m.add(c"getState", get_state);
+        m.add(c"get_id", get_id);
+        m.add(c"getId", get_id);
+        m.add(c"get_send_handle", get_send_handle);
+        m.add(c"getSendHandle", get_send_handle);
+        m.add(c"get_protocol", get_protocol);
+        m.add(c"getProtocol", get_protocol);
+        m.add(c"get_response_headers", get_response_headers);
+        m.add(c"getResponseHeaders", get_response_headers);
+        m.add(c"get_local_address", get_local_address);
+        m.add(c"getLocalAddress", get_local_address);
+        m.add(c"get_last_activity", get_last_activity);
+        m.add(c"getLastActivity", get_last_activity);
+        m.add(c"get_last_received", get_last_received);
+        m.add(c"getLastReceived", get_last_received);
+        m.add(c"get_last_sent", get_last_sent);
+        m.add(c"getLastSent", get_last_sent);
+        m.add(c"get_connected_at", get_connected_at);
+        m.add(c"getConnectedAt", get_connected_at);
+        m.add(c"get_uptime", get_uptime);
+        m.add(c"getUptime", get_uptime);
+        m.add(c"get_rtt", get_rtt);
+        m.add(c"getRtt", get_rtt);
+        m.add(c"get_health", get_health);
+        m.add(c"getHealth", get_health);
+        m.add(c"get_send_count", get_send_count);
+        m.add(c"getSendCount", get_send_count);
+        m.add(c"get_receive_count", get_receive_count);
+        m.add(c"getReceiveCount", get_receive_count);
+        m.add(c"get_inbound_queue_depth", get_inbound_queue_depth);
+        m.add(c"getInboundQueueDepth", get_inbound_queue_depth);
+        m.add(c"get_stats", get_stats);
+        m.add(c"getStats", get_stats);
+        m.add(c"get_size_histogram", get_size_histogram);
+        m.add(c"getSizeHistogram", get_size_histogram);
+        m.add(c"set_auto_reconnect", set_auto_reconnect);
+        m.add(c"setAutoReconnect", set_auto_reconnect);
+        m.add(c"get_config", get_config);
+        m.add(c"getConfig", get_config);
+        m.add(c"set_callbacks", set_callbacks);
+        m.add(c"setCallbacks", set_callbacks);
+        m.add(c"has_listener", has_listener);
+        m.add(c"hasListener", has_listener);
+        m.add(c"get_listeners", get_listeners);
+        m.add(c"getListeners", get_listeners);
     }
 
     fn meta_methods(m: &mut Methods) {
@@ -61,3 +561,47 @@ impl UserData for Socket {
         "tungstenite"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_frame_keeps_opcode_fin_and_payload() {
+        let frame = build_frame(1, b"hi".to_vec(), false).unwrap();
+        assert_eq!(frame.header().opcode, OpCode::Data(Data::Text));
+        assert!(!frame.header().is_final);
+        assert_eq!(frame.payload(), b"hi");
+
+        let frame = build_frame(0, Vec::new(), true).unwrap();
+        assert_eq!(frame.header().opcode, OpCode::Data(Data::Continue));
+        assert!(frame.header().is_final);
+    }
+
+    #[test]
+    fn build_frame_accepts_control_frames() {
+        let ping = build_frame(9, vec![0; MAX_CONTROL_PAYLOAD], true).unwrap();
+        assert_eq!(ping.header().opcode, OpCode::Control(Control::Ping));
+
+        let pong = build_frame(10, Vec::new(), true).unwrap();
+        assert_eq!(pong.header().opcode, OpCode::Control(Control::Pong));
+
+        let close = build_frame(8, 1000u16.to_be_bytes().to_vec(), true).unwrap();
+        assert_eq!(close.header().opcode, OpCode::Control(Control::Close));
+    }
+
+    #[test]
+    fn build_frame_rejects_invalid_frames() {
+        // out of range, reserved data and reserved control opcodes
+        assert!(build_frame(16, Vec::new(), true).is_err());
+        assert!(build_frame(3, Vec::new(), true).is_err());
+        assert!(build_frame(11, Vec::new(), true).is_err());
+
+        // fragmented or oversized control frames
+        assert!(build_frame(9, Vec::new(), false).is_err());
+        assert!(build_frame(10, vec![0; MAX_CONTROL_PAYLOAD + 1], true).is_err());
+
+        // a close payload can't be just half a code
+        assert!(build_frame(8, vec![0x03], true).is_err());
+    }
+}